@@ -1,8 +1,24 @@
 // We need to forward routine registration from C to Rust
 // to avoid the linker removing the static library.
 
+#include <Rinternals.h>
+#include <R_ext/Utils.h>
+
 void R_init_zoomerjoin_extendr(void *dll);
 
 void R_init_zoomerjoin(void *dll) {
     R_init_zoomerjoin_extendr(dll);
 }
+
+static void check_interrupt(void *data) {
+    (void) data;
+    R_CheckUserInterrupt();
+}
+
+// Whether the user has asked R to interrupt. R_CheckUserInterrupt() jumps
+// out of the caller on an interrupt, which Rust frames must not be unwound
+// by, so it runs under R_ToplevelExec(), which returns FALSE instead.
+// Must only be called from R's main thread.
+int zoomerjoin_interrupt_pending(void) {
+    return !R_ToplevelExec(check_interrupt, NULL);
+}
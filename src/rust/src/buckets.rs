@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use dashmap::DashMap;
+
+/// A cap on the size of the buckets a join verifies. A band key that most
+/// records share, such as one built from a shingle like "llc", makes a
+/// bucket of most of one side, and every record probing it would be
+/// compared with all of them. Buckets of more than `max_size` records are
+/// skipped instead, which bounds the work per probe at the cost of the
+/// matches only those buckets would have found. The skipped buckets, and
+/// the candidate pairs their probes would have verified, are counted so the
+/// join can report them.
+#[derive(Debug, Default)]
+pub struct BucketLimit {
+    max_size: Option<usize>,
+    buckets: AtomicUsize,
+    pairs: AtomicUsize,
+}
+
+impl BucketLimit {
+    /// A limit of `max_size` records per bucket, or none for `None`.
+    pub fn new(max_size: Option<usize>) -> Self {
+        Self {
+            max_size,
+            ..Self::default()
+        }
+    }
+
+    /// Whether a bucket of `size` records may be verified. A probe that is
+    /// refused counts the `size` pairs it skips.
+    #[inline]
+    pub fn admits(&self, size: usize) -> bool {
+        self.admits_pairs(size, size)
+    }
+
+    /// Like `admits`, for a probe that would verify only `pairs` of the
+    /// bucket's records, as a self join's probes verify only the records
+    /// before them.
+    #[inline]
+    pub fn admits_pairs(&self, size: usize, pairs: usize) -> bool {
+        match self.max_size {
+            Some(max_size) if size > max_size => {
+                self.pairs.fetch_add(pairs, Ordering::Relaxed);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Count the oversized buckets of one band, once it has been filled.
+    pub fn count_buckets(&self, store: &DashMap<u64, Vec<usize>>) {
+        if let Some(max_size) = self.max_size {
+            let oversized = store
+                .iter()
+                .filter(|bucket| bucket.len() > max_size)
+                .count();
+            self.buckets.fetch_add(oversized, Ordering::Relaxed);
+        }
+    }
+
+    /// The number of buckets skipped, counted once per band.
+    pub fn dropped_buckets(&self) -> usize {
+        self.buckets.load(Ordering::Relaxed)
+    }
+
+    /// The number of candidate pairs skipped, counted once per band they
+    /// would have been verified in.
+    pub fn dropped_pairs(&self) -> usize {
+        self.pairs.load(Ordering::Relaxed)
+    }
+}
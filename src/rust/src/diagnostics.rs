@@ -0,0 +1,129 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use dashmap::DashMap;
+use extendr_api::prelude::*;
+use ndarray::Array2;
+use rustc_hash::FxHashMap;
+
+/// What one band of an LSH join cost and what it found.
+#[derive(Debug, Clone, Copy)]
+pub struct BandReport {
+    /// The number of buckets the indexed side fell into.
+    pub buckets: usize,
+    /// The number of records in the largest of those buckets.
+    pub largest_bucket: usize,
+    /// Pairs that shared a bucket, including pairs already found in earlier
+    /// bands and pairs in buckets a `BucketLimit` refused.
+    pub candidates: usize,
+    /// Candidates whose similarity or distance was computed.
+    pub verified: usize,
+    /// Pairs added to the output for the first time.
+    pub accepted: usize,
+    /// Wall time spent on the band, in seconds.
+    pub seconds: f64,
+}
+
+/// The work one probing record did in a band. Records tally their own
+/// counts and add them once, so the shared counters are not contended for
+/// every pair.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProbeCounts {
+    pub candidates: usize,
+    pub verified: usize,
+    pub accepted: usize,
+}
+
+/// Per-band diagnostics of an LSH join, for tuning `n_bands` and
+/// `band_width`: how the indexed side spread over buckets, and how many
+/// pairs each band proposed, verified and accepted.
+#[derive(Debug, Default)]
+pub struct JoinDiagnostics {
+    bands: Mutex<Vec<BandReport>>,
+    candidates: AtomicUsize,
+    verified: AtomicUsize,
+    accepted: AtomicUsize,
+}
+
+impl JoinDiagnostics {
+    /// Add the counts of one probing record to the current band.
+    #[inline]
+    pub fn add(&self, counts: ProbeCounts) {
+        self.candidates
+            .fetch_add(counts.candidates, Ordering::Relaxed);
+        self.verified.fetch_add(counts.verified, Ordering::Relaxed);
+        self.accepted.fetch_add(counts.accepted, Ordering::Relaxed);
+    }
+
+    /// Close the band that started at `started`, while `store` still holds
+    /// its buckets. With `progress`, also print what the band did.
+    pub fn finish_band(&self, started: Instant, store: &DashMap<u64, Vec<usize>>, progress: bool) {
+        let largest_bucket = store.iter().map(|bucket| bucket.len()).max();
+        let report = self.push(started, store.len(), largest_bucket.unwrap_or(0));
+        if progress {
+            rprintln!(
+                "band done in {:.2}s: {} candidates, {} verified, {} accepted",
+                report.seconds,
+                report.candidates,
+                report.verified,
+                report.accepted
+            );
+        }
+    }
+
+    /// Close the band that started at `started`, whose buckets are one of
+    /// the tables of an `LshIndex`.
+    pub fn finish_table(&self, started: Instant, table: &FxHashMap<u64, Vec<usize>>) {
+        let largest_bucket = table.values().map(Vec::len).max();
+        self.push(started, table.len(), largest_bucket.unwrap_or(0));
+    }
+
+    fn push(&self, started: Instant, buckets: usize, largest_bucket: usize) -> BandReport {
+        let report = BandReport {
+            buckets,
+            largest_bucket,
+            candidates: self.candidates.swap(0, Ordering::Relaxed),
+            verified: self.verified.swap(0, Ordering::Relaxed),
+            accepted: self.accepted.swap(0, Ordering::Relaxed),
+            seconds: started.elapsed().as_secs_f64(),
+        };
+        self.push_report(report);
+        report
+    }
+
+    /// Record the report of a band that was tallied elsewhere, for joins
+    /// that do not work through their bands one at a time.
+    pub fn push_report(&self, report: BandReport) {
+        self.bands.lock().unwrap().push(report);
+    }
+
+    /// Replace the accepted counts of the bands finished so far, for joins
+    /// that only know which pairs they keep once every band is done.
+    pub fn set_accepted(&self, accepted: &[usize]) {
+        let mut bands = self.bands.lock().unwrap();
+        for (band, accepted) in bands.iter_mut().zip(accepted) {
+            band.accepted = *accepted;
+        }
+    }
+
+    /// The reports of the bands finished so far, in order.
+    pub fn bands(&self) -> Vec<BandReport> {
+        self.bands.lock().unwrap().clone()
+    }
+
+    /// One row per band, with the columns of `BandReport` in order.
+    pub fn to_matrix(&self) -> Array2<f64> {
+        let bands = self.bands();
+        let mut out = Array2::zeros((bands.len(), 6));
+        for (i, band) in bands.iter().enumerate() {
+            out[[i, 0]] = band.buckets as f64;
+            out[[i, 1]] = band.largest_bucket as f64;
+            out[[i, 2]] = band.candidates as f64;
+            out[[i, 3]] = band.verified as f64;
+            out[[i, 4]] = band.accepted as f64;
+            out[[i, 5]] = band.seconds;
+        }
+        out
+    }
+}
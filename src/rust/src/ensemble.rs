@@ -0,0 +1,432 @@
+use std::cmp::Ordering;
+
+use extendr_api::prelude::*;
+use nohash_hasher::IntMap;
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use std::result::Result;
+
+use crate::diagnostics::{BandReport, JoinDiagnostics, ProbeCounts};
+use crate::interrupt::{self, interrupted, Interrupted};
+use crate::signatures::Signatures;
+
+/// Steps of the grid of Jaccard thresholds that band configurations are
+/// tuned for.
+const GRID_STEPS: usize = 100;
+
+/// How much a missed match counts against a band configuration, relative to
+/// a false candidate at weight `1 - FALSE_NEGATIVE_WEIGHT`. Candidates are
+/// verified exactly, so a false candidate only costs one comparison.
+const FALSE_NEGATIVE_WEIGHT: f64 = 0.9;
+
+/// An LSH Ensemble (Zhu et al., 2016) over the signatures of one side of a
+/// join, for finding the records that contain a query set.
+///
+/// Containment `|Q ∩ X| / |Q|` reaching `t` implies a Jaccard similarity of
+/// at least `t|Q| / (|Q| + |X| - t|Q|)`, which falls as `|X|` grows. Records
+/// are therefore split into partitions of similar size, each of which is
+/// searched at the Jaccard threshold given by its largest set, so that large
+/// sets do not force a low threshold onto small ones.
+///
+/// Each partition keeps, for every band, its records sorted by their values
+/// in that band, as in an LSH Forest. Records sharing the first `r` values of
+/// a band with the query then form one contiguous run, so the band width and
+/// the number of bands used can be picked per query and partition, out of
+/// the `n_bands` bands of `band_size` values in the signatures.
+pub struct LshEnsemble<'a> {
+    signatures: &'a Signatures,
+    n_bands: usize,
+    band_size: usize,
+    partitions: Vec<Partition>,
+    /// The `(bands, rows)` to search with for each threshold on the grid.
+    configs: Vec<(usize, usize)>,
+}
+
+struct Partition {
+    /// The largest set size in the partition.
+    upper: usize,
+    /// For each band, the partition's records sorted by their band values.
+    forest: Vec<Vec<usize>>,
+}
+
+impl<'a> LshEnsemble<'a> {
+    /// Index `signatures`, whose records have `sizes` shingles, in
+    /// `n_partitions` partitions of equal count.
+    pub fn new(
+        signatures: &'a Signatures,
+        sizes: &[usize],
+        n_bands: usize,
+        band_size: usize,
+        n_partitions: usize,
+        pool: &ThreadPool,
+    ) -> Self {
+        let mut by_size: Vec<usize> = (0..sizes.len()).collect();
+        by_size.sort_by_key(|&record| sizes[record]);
+
+        let n_partitions = n_partitions.max(1);
+        let chunk_size = (by_size.len() + n_partitions - 1) / n_partitions;
+        let partitions = pool.install(|| {
+            by_size
+                .chunks(chunk_size.max(1))
+                .map(|records| Partition {
+                    upper: sizes[*records.last().unwrap()],
+                    forest: (0..n_bands)
+                        .into_par_iter()
+                        .map(|band| {
+                            let mut sorted = records.to_vec();
+                            sorted.sort_by(|&x, &y| {
+                                compare(signatures, x, signatures, y, band, band_size, band_size)
+                            });
+                            sorted
+                        })
+                        .collect(),
+                })
+                .collect()
+        });
+
+        let configs = (0..=GRID_STEPS)
+            .map(|step| optimal_config(step as f64 / GRID_STEPS as f64, n_bands, band_size))
+            .collect();
+
+        Self {
+            signatures,
+            n_bands,
+            band_size,
+            partitions,
+            configs,
+        }
+    }
+
+    /// The records that may contain query `query` of `queries`, a set of
+    /// `size` shingles, with containment `threshold` or more, each with the
+    /// first band that proposed it. What every band proposed is added to
+    /// `counts`.
+    pub fn candidates(
+        &self,
+        queries: &Signatures,
+        query: usize,
+        size: usize,
+        threshold: f64,
+        counts: &mut [ProbeCounts],
+    ) -> IntMap<usize, usize> {
+        let mut candidates = IntMap::default();
+        if size == 0 {
+            return candidates;
+        }
+
+        let overlap = threshold * size as f64;
+        for partition in &self.partitions {
+            // No set smaller than the overlap can contain enough of the query.
+            if (partition.upper as f64) < overlap {
+                continue;
+            }
+            let jaccard = overlap / (size as f64 + partition.upper as f64 - overlap);
+            // Round down onto the grid, which only lowers the threshold.
+            let step = ((jaccard * GRID_STEPS as f64).floor() as usize).min(GRID_STEPS);
+            let (n_bands, rows) = self.configs[step];
+
+            for (band, sorted) in partition.forest.iter().enumerate().take(n_bands) {
+                let cmp = |&record: &usize| {
+                    compare(
+                        self.signatures,
+                        record,
+                        queries,
+                        query,
+                        band,
+                        self.band_size,
+                        rows,
+                    )
+                };
+                let start = sorted.partition_point(|record| cmp(record) == Ordering::Less);
+                let end = sorted.partition_point(|record| cmp(record) != Ordering::Greater);
+                counts[band].candidates += end - start;
+                for record in &sorted[start..end] {
+                    candidates.entry(*record).or_insert(band);
+                }
+            }
+        }
+        candidates
+    }
+
+    /// The number of buckets band `band` splits the records into across
+    /// partitions, where a bucket holds the records of a partition that
+    /// agree on the whole band, and the number of records in the largest.
+    fn buckets(&self, band: usize) -> (usize, usize) {
+        let mut buckets = 0;
+        let mut largest = 0;
+        for partition in &self.partitions {
+            let sorted = &partition.forest[band];
+            let mut start = 0;
+            while start < sorted.len() {
+                let run = sorted[start..]
+                    .iter()
+                    .take_while(|&&record| {
+                        compare(
+                            self.signatures,
+                            sorted[start],
+                            self.signatures,
+                            record,
+                            band,
+                            self.band_size,
+                            self.band_size,
+                        )
+                        .is_eq()
+                    })
+                    .count();
+                buckets += 1;
+                largest = largest.max(run);
+                start += run;
+            }
+        }
+        (buckets, largest)
+    }
+}
+
+/// Compare the first `rows` values of band `band` of two signatures.
+#[inline]
+fn compare(
+    a: &Signatures,
+    a_record: usize,
+    b: &Signatures,
+    b_record: usize,
+    band: usize,
+    band_size: usize,
+    rows: usize,
+) -> Ordering {
+    let start = band * band_size;
+    (start..start + rows)
+        .map(|j| a.value(a_record, j).cmp(&b.value(b_record, j)))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+/// The number of bands and rows per band, out of at most `max_bands` bands
+/// of `max_rows` rows, that minimise the weighted sum of the false positive
+/// and false negative probability masses around a Jaccard `threshold`, as in
+/// LSH Ensemble tuning.
+fn optimal_config(threshold: f64, max_bands: usize, max_rows: usize) -> (usize, usize) {
+    let integrate = |from: f64, to: f64, f: &dyn Fn(f64) -> f64| {
+        let steps = 50;
+        let width = (to - from) / steps as f64;
+        (0..steps)
+            .map(|i| f(from + (i as f64 + 0.5) * width))
+            .sum::<f64>()
+            * width
+    };
+
+    let mut best = (max_bands, max_rows);
+    let mut best_error = f64::INFINITY;
+    for rows in 1..=max_rows {
+        for bands in 1..=max_bands {
+            let p = |s: f64| 1.0 - (1.0 - s.powi(rows as i32)).powi(bands as i32);
+            let error = (1.0 - FALSE_NEGATIVE_WEIGHT) * integrate(0.0, threshold, &p)
+                + FALSE_NEGATIVE_WEIGHT * integrate(threshold, 1.0, &|s| 1.0 - p(s));
+            if error < best_error {
+                best_error = error;
+                best = (bands, rows);
+            }
+        }
+    }
+    best
+}
+
+/// Containment join of `queries` into `ensemble`: the `(query, record,
+/// containment)` triples whose exact `containment` reaches `threshold`, or
+/// `Interrupted` if the user interrupts. Queries search a different number
+/// of bands depending on their size, so what each band proposed, verified
+/// and accepted is tallied per query and recorded in `diagnostics` once
+/// every query is done. A candidate is verified by the first band that
+/// proposed it.
+#[allow(clippy::too_many_arguments)]
+pub fn ensemble_join<F>(
+    ensemble: &LshEnsemble,
+    queries: &Signatures,
+    sizes: &[usize],
+    threshold: f64,
+    diagnostics: &JoinDiagnostics,
+    progress: bool,
+    pool: &ThreadPool,
+    containment: F,
+) -> Result<Vec<(usize, usize, f64)>, Interrupted>
+where
+    F: Fn(usize, usize) -> f64 + Sync,
+{
+    if progress {
+        rprintln!(
+            "Querying {} records across {} bands",
+            queries.n_records(),
+            ensemble.n_bands
+        );
+    }
+
+    let n_bands = ensemble.n_bands;
+    let (matches, counts) = interrupt::install(pool, || {
+        (0..queries.n_records())
+            .into_par_iter()
+            .filter(|_| !interrupted())
+            .fold(
+                || (Vec::new(), vec![ProbeCounts::default(); n_bands]),
+                |(mut matches, mut counts), query| {
+                    let candidates =
+                        ensemble.candidates(queries, query, sizes[query], threshold, &mut counts);
+                    for (record, band) in candidates {
+                        counts[band].verified += 1;
+                        let score = containment(query, record);
+                        if score >= threshold {
+                            counts[band].accepted += 1;
+                            matches.push((query, record, score));
+                        }
+                    }
+                    (matches, counts)
+                },
+            )
+            .reduce(
+                || (Vec::new(), vec![ProbeCounts::default(); n_bands]),
+                |(mut matches, mut counts), (more_matches, more_counts)| {
+                    matches.extend(more_matches);
+                    for (total, more) in counts.iter_mut().zip(more_counts) {
+                        total.candidates += more.candidates;
+                        total.verified += more.verified;
+                        total.accepted += more.accepted;
+                    }
+                    (matches, counts)
+                },
+            )
+    })?;
+
+    for (band, counts) in counts.into_iter().enumerate() {
+        let (buckets, largest_bucket) = ensemble.buckets(band);
+        diagnostics.push_report(BandReport {
+            buckets,
+            largest_bucket,
+            candidates: counts.candidates,
+            verified: counts.verified,
+            accepted: counts.accepted,
+            seconds: f64::NAN,
+        });
+    }
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::arr2;
+    use rayon::ThreadPoolBuilder;
+
+    fn pool() -> ThreadPool {
+        ThreadPoolBuilder::new().num_threads(2).build().unwrap()
+    }
+
+    /// Four records with two bands of two values each.
+    fn signatures() -> Signatures {
+        Signatures::from_matrix(
+            arr2(&[
+                [1.0, 2.0, 3.0, 4.0],
+                [1.0, 2.0, 9.0, 9.0],
+                [7.0, 7.0, 3.0, 4.0],
+                [8.0, 8.0, 8.0, 8.0],
+            ])
+            .view(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn configs_stay_in_bounds_and_narrow_as_the_threshold_rises() {
+        for threshold in [0.0, 0.2, 0.5, 0.8, 1.0] {
+            let (bands, rows) = optimal_config(threshold, 8, 4);
+            assert!((1..=8).contains(&bands));
+            assert!((1..=4).contains(&rows));
+        }
+        let (low_bands, low_rows) = optimal_config(0.1, 8, 4);
+        let (high_bands, high_rows) = optimal_config(0.9, 8, 4);
+        assert!(high_rows >= low_rows);
+        assert!(high_bands as f64 / high_rows as f64 <= low_bands as f64 / low_rows as f64);
+    }
+
+    #[test]
+    fn candidates_agree_with_the_query_on_a_band() {
+        let records = signatures();
+        let ensemble = LshEnsemble::new(&records, &[10; 4], 2, 2, 1, &pool());
+
+        for threshold in [0.1, 0.5, 0.9, 1.0] {
+            let mut counts = vec![ProbeCounts::default(); 2];
+            let candidates = ensemble.candidates(&records, 0, 10, threshold, &mut counts);
+            assert_eq!(candidates.get(&0), Some(&0), "threshold {threshold}");
+            assert!(!candidates.contains_key(&3), "threshold {threshold}");
+            let proposed: usize = counts.iter().map(|counts| counts.candidates).sum();
+            assert!(proposed >= candidates.len());
+        }
+
+        let mut counts = vec![ProbeCounts::default(); 2];
+        assert!(ensemble
+            .candidates(&records, 0, 0, 0.5, &mut counts)
+            .is_empty());
+    }
+
+    #[test]
+    fn partitions_too_small_to_contain_the_query_are_skipped() {
+        let records = Signatures::from_matrix(
+            arr2(&[
+                [1.0, 2.0, 3.0, 4.0],
+                [5.0, 5.0, 5.0, 5.0],
+                [1.0, 2.0, 3.0, 4.0],
+                [6.0, 6.0, 6.0, 6.0],
+            ])
+            .view(),
+        )
+        .unwrap();
+        let ensemble = LshEnsemble::new(&records, &[2, 2, 10, 10], 2, 2, 2, &pool());
+
+        let mut counts = vec![ProbeCounts::default(); 2];
+        let candidates = ensemble.candidates(&records, 0, 10, 0.5, &mut counts);
+        assert!(!candidates.contains_key(&0));
+        assert!(candidates.contains_key(&2));
+    }
+
+    #[test]
+    fn buckets_count_runs_of_equal_bands() {
+        let records = signatures();
+        let ensemble = LshEnsemble::new(&records, &[10; 4], 2, 2, 1, &pool());
+        assert_eq!(ensemble.buckets(0), (3, 2));
+        assert_eq!(ensemble.buckets(1), (3, 2));
+
+        // Records 0 and 1 agree on band 0 but fall into different
+        // partitions, so they no longer share a bucket there.
+        let ensemble = LshEnsemble::new(&records, &[1, 2, 1, 2], 2, 2, 2, &pool());
+        assert_eq!(ensemble.buckets(0), (4, 1));
+        assert_eq!(ensemble.buckets(1), (3, 2));
+    }
+
+    #[test]
+    fn joins_report_every_band_without_timings() {
+        let records = signatures();
+        let sizes = [10; 4];
+        let ensemble = LshEnsemble::new(&records, &sizes, 2, 2, 1, &pool());
+        let diagnostics = JoinDiagnostics::default();
+
+        let mut matches = ensemble_join(
+            &ensemble,
+            &records,
+            &sizes,
+            0.5,
+            &diagnostics,
+            false,
+            &pool(),
+            |query, record| if query == record { 1.0 } else { 0.0 },
+        )
+        .unwrap();
+        matches.sort_by_key(|(query, record, _)| (*query, *record));
+        assert_eq!(
+            matches,
+            vec![(0, 0, 1.0), (1, 1, 1.0), (2, 2, 1.0), (3, 3, 1.0)]
+        );
+
+        let bands = diagnostics.bands();
+        assert_eq!(bands.len(), 2);
+        assert_eq!(bands.iter().map(|band| band.accepted).sum::<usize>(), 4);
+        assert!(bands.iter().all(|band| band.verified <= band.candidates));
+        assert!(bands.iter().all(|band| band.seconds.is_nan()));
+    }
+}
@@ -3,6 +3,8 @@ use ndarray_rand::rand_distr::{Normal, Uniform};
 use ndarray_rand::RandomExt;
 use rand::Rng;
 use rustc_hash::FxHasher;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
 use std::hash::{Hash, Hasher};
 
 #[derive(Debug)]
@@ -25,15 +27,132 @@ impl EuclidianHasher {
         }
     }
 
+    /// The projections of `x`, in units of `r`. Each projection falls in the
+    /// slot given by its ceiling, and every projection at or below 0 in
+    /// slot 0.
+    fn project(&self, x: ArrayView1<f64>) -> Array1<f64> {
+        (x.dot(&self.a_vectors) + &self.b_vectors) / self.r
+    }
+
     pub fn hash(&self, x: ArrayView1<f64>) -> u64 {
-        let numerator = x.dot(&self.a_vectors) + &self.b_vectors;
+        let rounded = self.project(x).map(|x| x.ceil() as u64);
+        bucket_key(rounded.view())
+    }
+
+    /// The bucket of `x` followed by up to `n_probes` neighbouring buckets,
+    /// in the order of multi-probe LSH (Lv et al., 2007). A neighbouring
+    /// bucket moves some slots of the bucket one step down or up, and buckets
+    /// are ranked by the summed squared distances from the projections of
+    /// `x` to the slot boundaries they cross, so the buckets a nearby point
+    /// most likely hashes to come first.
+    pub fn hash_probes(&self, x: ArrayView1<f64>, n_probes: usize) -> Vec<u64> {
+        let projections = self.project(x);
+        let mut slots = projections.map(|x| x.ceil() as u64);
+
+        let mut keys = Vec::with_capacity(n_probes + 1);
+        keys.push(bucket_key(slots.view()));
+        if n_probes == 0 {
+            return keys;
+        }
+
+        // Every single-slot step, as (score, slot, step), cheapest first.
+        // Slot 0 has no slot below it.
+        let mut steps: Vec<(f64, usize, i64)> = Vec::with_capacity(2 * slots.len());
+        for (slot, (f, h)) in projections.iter().zip(&slots).enumerate() {
+            let below = f - (*h as f64 - 1.0);
+            if *h > 0 {
+                steps.push((below * below, slot, -1));
+            }
+            if *h < u64::MAX {
+                steps.push(((1.0 - below) * (1.0 - below), slot, 1));
+            }
+        }
+        if steps.is_empty() {
+            return keys;
+        }
+        steps.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        // Sets of steps are generated in order of score from the cheapest
+        // step by shifting or extending the last step of each set taken.
+        let mut heap = BinaryHeap::new();
+        heap.push(StepSet {
+            score: steps[0].0,
+            steps: vec![0],
+        });
+        while keys.len() <= n_probes {
+            let set = match heap.pop() {
+                Some(set) => set,
+                None => break,
+            };
+
+            let last = *set.steps.last().unwrap();
+            if last + 1 < steps.len() {
+                let mut shifted = set.steps.clone();
+                *shifted.last_mut().unwrap() = last + 1;
+                heap.push(StepSet {
+                    score: set.score - steps[last].0 + steps[last + 1].0,
+                    steps: shifted,
+                });
+
+                let mut extended = set.steps.clone();
+                extended.push(last + 1);
+                heap.push(StepSet {
+                    score: set.score + steps[last + 1].0,
+                    steps: extended,
+                });
+            }
 
-        let rounded = (numerator / self.r).map(|x| x.ceil() as u64);
+            // A set that moves the same slot both ways is not a bucket.
+            let moves_each_slot_once = set.steps.iter().all(|&a| {
+                set.steps
+                    .iter()
+                    .all(|&b| a == b || steps[a].1 != steps[b].1)
+            });
+            if moves_each_slot_once {
+                for &step in &set.steps {
+                    let (_, slot, by) = steps[step];
+                    slots[slot] = slots[slot].wrapping_add_signed(by);
+                }
+                keys.push(bucket_key(slots.view()));
+                for &step in &set.steps {
+                    let (_, slot, by) = steps[step];
+                    slots[slot] = slots[slot].wrapping_add_signed(-by);
+                }
+            }
+        }
+        keys
+    }
+}
+
+fn bucket_key(slots: ArrayView1<u64>) -> u64 {
+    let mut hasher = FxHasher::default();
+    slots.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A set of slot steps, ordered so that a `BinaryHeap` pops the set with the
+/// lowest score first.
+struct StepSet {
+    score: f64,
+    steps: Vec<usize>,
+}
+
+impl PartialEq for StepSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
 
-        let mut hasher = FxHasher::default();
+impl Eq for StepSet {}
 
-        rounded.hash(&mut hasher);
+impl PartialOrd for StepSet {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
-        hasher.finish()
+impl Ord for StepSet {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.score.total_cmp(&self.score)
     }
 }
@@ -0,0 +1,605 @@
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::time::Instant;
+
+use dashmap::DashMap;
+use nohash_hasher::IntSet;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use rustc_hash::FxHashMap;
+
+use crate::diagnostics::{JoinDiagnostics, ProbeCounts};
+use crate::interrupt::{self, interrupted, Interrupted};
+use crate::minihasher::{HashScheme, MinHasher, OnePermutationHasher, SignatureHasher};
+use crate::normalize::Normalizer;
+use crate::phonetic::PhoneticEncoder;
+use crate::shingleset::{ShingleConfig, ShingleId, ShingleSet, Tokenizer};
+use crate::signatures::Signatures;
+
+/// Bytes that every saved index starts with, followed by the format version.
+const MAGIC: &[u8; 8] = b"ZJLSHIDX";
+const VERSION: u32 = 4;
+
+/// The version of the hash functions that shingle ids and signatures are
+/// computed with: FxHasher from rustc-hash 1.x for shingle ids and classic
+/// MinHash, and splitmix64 for one permutation hashing. Bump it whenever any
+/// of them changes, so that an index saved by an older build is rejected
+/// rather than probed with hashes that no longer match its buckets.
+const HASHING_VERSION: u32 = 1;
+
+/// The longest signature, `n_bands * band_size`, that a saved index may
+/// declare. Far beyond any useful setting, it stops a corrupt header from
+/// allocating a hasher of arbitrary size.
+const MAX_SIGNATURE_LEN: usize = 1 << 20;
+
+/// The widest n-gram a saved index may declare. Padding a string takes
+/// memory in proportion to the width, so a corrupt width must not reach the
+/// shingler.
+const MAX_NGRAM_WIDTH: usize = 1 << 16;
+
+/// Counts read from a file reserve at most this many entries up front, so
+/// that a corrupt count fails on the missing data instead of on allocation.
+const MAX_RESERVE: usize = 1 << 16;
+
+/// A MinHash LSH index over a collection of strings. The index keeps one
+/// bucket table per band, mapping band keys to the ids of the records that
+/// fall into them, and the shingle set of every record so that candidates can
+/// be verified exactly. Queries only shingle and sign the probe strings and
+/// look them up in the tables, so the indexed side is never rebuilt.
+///
+/// Signatures are drawn from `seed` exactly as `MinHashJoiner::join` draws
+/// them, and records are identified by the order they were inserted in. A
+/// saved index stores the hash functions that were drawn rather than `seed`,
+/// so it does not depend on the random number generator of the build that
+/// loads it. The bucket tables are saved as every record's key in every
+/// band, so that loading refills them without shingling or hashing.
+pub struct LshIndex<T: ShingleId> {
+    config: ShingleConfig,
+    scheme: HashScheme,
+    n_bands: usize,
+    band_size: usize,
+    hasher: SignatureHasher,
+    sets: Vec<ShingleSet<T>>,
+    tables: Vec<FxHashMap<u64, Vec<usize>>>,
+}
+
+impl<T: ShingleId> LshIndex<T> {
+    /// An empty index.
+    pub fn new(
+        config: ShingleConfig,
+        scheme: HashScheme,
+        n_bands: usize,
+        band_size: usize,
+        seed: u64,
+    ) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let hasher = SignatureHasher::new(scheme, n_bands * band_size, &mut rng);
+        Self::with_hasher(config, scheme, n_bands, band_size, hasher)
+    }
+
+    fn with_hasher(
+        config: ShingleConfig,
+        scheme: HashScheme,
+        n_bands: usize,
+        band_size: usize,
+        hasher: SignatureHasher,
+    ) -> Self {
+        Self {
+            config,
+            scheme,
+            n_bands,
+            band_size,
+            hasher,
+            sets: Vec::new(),
+            tables: vec![FxHashMap::default(); n_bands],
+        }
+    }
+
+    pub fn n_records(&self) -> usize {
+        self.sets.len()
+    }
+
+    /// Add `strings` to the index and return the ids they were given. If the
+    /// user interrupts while they are shingled or signed, the index is left
+    /// as it was and `Interrupted` is returned.
+    pub fn insert(
+        &mut self,
+        strings: &[&str],
+        pool: &ThreadPool,
+    ) -> Result<Range<usize>, Interrupted> {
+        let start = self.sets.len();
+        let config = &self.config;
+        let new_sets: Vec<ShingleSet<T>> = interrupt::install(pool, || {
+            strings
+                .par_iter()
+                .enumerate()
+                .filter(|_| !interrupted())
+                .map(|(i, x)| ShingleSet::new(x, config, start + i, None))
+                .collect()
+        })?;
+
+        self.file(start, &new_sets, pool)?;
+        self.sets.extend(new_sets);
+        Ok(start..self.sets.len())
+    }
+
+    /// Sign `sets`, the records with ids from `start` on, and file them into
+    /// the bucket tables.
+    fn file(
+        &mut self,
+        start: usize,
+        sets: &[ShingleSet<T>],
+        pool: &ThreadPool,
+    ) -> Result<(), Interrupted> {
+        let hasher = &self.hasher;
+        let signatures = Signatures::compute(
+            sets.len(),
+            self.n_bands * self.band_size,
+            32,
+            pool,
+            |i, out| hasher.signature(&sets[i], out),
+        )?;
+
+        let band_size = self.band_size;
+        pool.install(|| {
+            self.tables
+                .par_iter_mut()
+                .enumerate()
+                .for_each(|(band, table)| {
+                    for record in 0..signatures.n_records() {
+                        table
+                            .entry(signatures.band_key(record, band, band_size))
+                            .or_default()
+                            .push(start + record);
+                    }
+                });
+        });
+        Ok(())
+    }
+
+    /// Look `strings` up in the index and return `(query, id, similarity)`
+    /// for every indexed record that shares a bucket with a query string in
+    /// at least one band and whose Jaccard similarity to it reaches
+    /// `threshold`. The tables are probed band by band, as `band_join` does,
+    /// and what each band found is recorded in `diagnostics`.
+    pub fn query(
+        &self,
+        strings: &[&str],
+        threshold: f64,
+        diagnostics: &JoinDiagnostics,
+        pool: &ThreadPool,
+    ) -> Result<Vec<(usize, usize, f64)>, Interrupted> {
+        let probes: Vec<ShingleSet<T>> = interrupt::install(pool, || {
+            strings
+                .par_iter()
+                .enumerate()
+                .filter(|_| !interrupted())
+                .map(|(i, x)| ShingleSet::new(x, &self.config, i, None))
+                .collect()
+        })?;
+
+        let signatures = Signatures::compute(
+            probes.len(),
+            self.n_bands * self.band_size,
+            32,
+            pool,
+            |i, out| self.hasher.signature(&probes[i], out),
+        )?;
+
+        let matched_pairs: DashMap<(usize, usize), f64> = DashMap::new();
+        for (band, table) in self.tables.iter().enumerate() {
+            let started = Instant::now();
+            interrupt::install(pool, || {
+                (0..probes.len()).into_par_iter().for_each(|query| {
+                    if interrupted() {
+                        return;
+                    }
+                    let mut counts = ProbeCounts::default();
+                    let key = signatures.band_key(query, band, self.band_size);
+                    if let Some(records) = table.get(&key) {
+                        counts.candidates += records.len();
+                        for record in records {
+                            if matched_pairs.contains_key(&(query, *record)) {
+                                continue;
+                            }
+                            counts.verified += 1;
+                            let similarity = probes[query].jaccard_similarity(&self.sets[*record]);
+                            if similarity >= threshold {
+                                matched_pairs.insert((query, *record), similarity);
+                                counts.accepted += 1;
+                            }
+                        }
+                    }
+                    diagnostics.add(counts);
+                });
+            })?;
+            diagnostics.finish_table(started, table);
+        }
+
+        Ok(matched_pairs
+            .into_iter()
+            .map(|((query, record), similarity)| (query, record, similarity))
+            .collect())
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_len(w, self.config.shingle_lens.len())?;
+        for width in &self.config.shingle_lens {
+            write_len(w, *width)?;
+        }
+        write_str(w, self.config.tokenizer.name())?;
+        let steps = self.config.normalizer.steps();
+        write_len(w, steps.len())?;
+        for step in steps {
+            write_str(w, step)?;
+        }
+        write_str(w, self.config.phonetic.map_or("", |encoder| encoder.name()))?;
+        w.write_all(&[self.config.pad as u8, self.config.multiset as u8])?;
+
+        write_str(w, self.scheme.name())?;
+        write_len(w, self.n_bands)?;
+        write_len(w, self.band_size)?;
+        w.write_all(&HASHING_VERSION.to_le_bytes())?;
+        match &self.hasher {
+            SignatureHasher::MinHash(hasher) => {
+                for seed in hasher.seeds() {
+                    write_u64(w, *seed)?;
+                }
+            }
+            SignatureHasher::OnePermutation(hasher) => write_u64(w, hasher.seed())?,
+        }
+
+        write_len(w, self.sets.len())?;
+        for set in &self.sets {
+            write_len(w, set.shingles.len())?;
+            for shingle in &set.shingles {
+                w.write_all(&shingle.as_u64().to_le_bytes()[..T::BITS as usize / 8])?;
+            }
+        }
+
+        // Every record falls into exactly one bucket of each band, so a band
+        // is written as the key of each record in id order.
+        let mut keys = vec![0u64; self.sets.len()];
+        for table in &self.tables {
+            for (key, records) in table {
+                for record in records {
+                    keys[*record] = *key;
+                }
+            }
+            for key in &keys {
+                write_u64(w, *key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read an index written by `write_body`, filing every record back into
+    /// the bucket tables under the keys that were saved with it.
+    fn read_body<R: Read>(r: &mut R) -> io::Result<Self> {
+        let n_widths = read_len(r)?;
+        let shingle_lens = (0..n_widths)
+            .map(|_| read_len(r))
+            .collect::<io::Result<Vec<usize>>>()?;
+        let tokenizer: Tokenizer = read_str(r)?.parse().map_err(invalid)?;
+        let n_steps = read_len(r)?;
+        let steps = (0..n_steps)
+            .map(|_| read_str(r))
+            .collect::<io::Result<Vec<String>>>()?;
+        let steps: Vec<&str> = steps.iter().map(String::as_str).collect();
+        let normalizer = Normalizer::from_steps(&steps).map_err(invalid)?;
+        let phonetic: Option<PhoneticEncoder> = match read_str(r)?.as_str() {
+            "" => None,
+            name => Some(name.parse().map_err(invalid)?),
+        };
+        let mut flags = [0u8; 2];
+        r.read_exact(&mut flags)?;
+        if shingle_lens.is_empty()
+            || !shingle_lens
+                .iter()
+                .all(|width| (1..=MAX_NGRAM_WIDTH).contains(width))
+        {
+            return Err(invalid("invalid n-gram widths"));
+        }
+
+        let config = ShingleConfig::new(shingle_lens, tokenizer)
+            .with_normalizer(normalizer)
+            .with_phonetic(phonetic)
+            .with_padding(flags[0] == 1)
+            .with_multiset(flags[1] == 1);
+
+        let scheme: HashScheme = read_str(r)?.parse().map_err(invalid)?;
+        let n_bands = read_len(r)?;
+        let band_size = read_len(r)?;
+        let len = match n_bands.checked_mul(band_size) {
+            Some(len) if (1..=MAX_SIGNATURE_LEN).contains(&len) => len,
+            _ => return Err(invalid("invalid number of bands or band size")),
+        };
+        let hashing = read_u32(r)?;
+        if hashing != HASHING_VERSION {
+            return Err(invalid(format!(
+                "the index was hashed with version {hashing} of the hash functions, \
+                 but this build uses version {HASHING_VERSION}"
+            )));
+        }
+        let hasher = match scheme {
+            HashScheme::MinHash => SignatureHasher::MinHash(MinHasher::from_seeds(
+                (0..len).map(|_| read_u64(r)).collect::<io::Result<_>>()?,
+            )),
+            HashScheme::OnePermutation => {
+                SignatureHasher::OnePermutation(OnePermutationHasher::from_seed(len, read_u64(r)?))
+            }
+        };
+        let mut index = Self::with_hasher(config, scheme, n_bands, band_size, hasher);
+
+        let n_records = read_len(r)?;
+        let mut sets = Vec::with_capacity(n_records.min(MAX_RESERVE));
+        for i in 0..n_records {
+            let n_shingles = read_len(r)?;
+            let mut shingles = IntSet::default();
+            shingles.reserve(n_shingles.min(MAX_RESERVE));
+            for _ in 0..n_shingles {
+                let mut bytes = [0u8; 8];
+                r.read_exact(&mut bytes[..T::BITS as usize / 8])?;
+                shingles.insert(T::from_hash(u64::from_le_bytes(bytes)));
+            }
+            sets.push(ShingleSet { shingles, index: i });
+        }
+
+        for table in &mut index.tables {
+            for record in 0..n_records {
+                table.entry(read_u64(r)?).or_default().push(record);
+            }
+        }
+        index.sets = sets;
+        Ok(index)
+    }
+}
+
+/// An index with either width of shingle id.
+pub enum MinHashIndex {
+    U32(LshIndex<u32>),
+    U64(LshIndex<u64>),
+}
+
+impl MinHashIndex {
+    pub fn n_records(&self) -> usize {
+        match self {
+            MinHashIndex::U32(index) => index.n_records(),
+            MinHashIndex::U64(index) => index.n_records(),
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        strings: &[&str],
+        pool: &ThreadPool,
+    ) -> Result<Range<usize>, Interrupted> {
+        match self {
+            MinHashIndex::U32(index) => index.insert(strings, pool),
+            MinHashIndex::U64(index) => index.insert(strings, pool),
+        }
+    }
+
+    pub fn query(
+        &self,
+        strings: &[&str],
+        threshold: f64,
+        diagnostics: &JoinDiagnostics,
+        pool: &ThreadPool,
+    ) -> Result<Vec<(usize, usize, f64)>, Interrupted> {
+        match self {
+            MinHashIndex::U32(index) => index.query(strings, threshold, diagnostics, pool),
+            MinHashIndex::U64(index) => index.query(strings, threshold, diagnostics, pool),
+        }
+    }
+
+    /// Write the index in a self-describing binary format: `MAGIC`, the
+    /// format version and the shingle id width, then the settings, the
+    /// version and parameters of the hash functions, the shingle sets, and
+    /// the band keys of every record. All integers are little-endian.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        match self {
+            MinHashIndex::U32(index) => {
+                w.write_all(&32u32.to_le_bytes())?;
+                index.write_body(w)
+            }
+            MinHashIndex::U64(index) => {
+                w.write_all(&64u32.to_le_bytes())?;
+                index.write_body(w)
+            }
+        }
+    }
+
+    /// Read an index written by `write_to`.
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a zoomerjoin index"));
+        }
+        let version = read_u32(r)?;
+        if version != VERSION {
+            return Err(invalid(format!("unsupported index version {version}")));
+        }
+        match read_u32(r)? {
+            32 => Ok(MinHashIndex::U32(LshIndex::read_body(r)?)),
+            64 => Ok(MinHashIndex::U64(LshIndex::read_body(r)?)),
+            bits => Err(invalid(format!("unsupported shingle id width {bits}"))),
+        }
+    }
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn write_u64<W: Write>(w: &mut W, x: u64) -> io::Result<()> {
+    w.write_all(&x.to_le_bytes())
+}
+
+fn write_len<W: Write>(w: &mut W, x: usize) -> io::Result<()> {
+    write_u64(w, x as u64)
+}
+
+fn write_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    write_len(w, s.len())?;
+    w.write_all(s.as_bytes())
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_len<R: Read>(r: &mut R) -> io::Result<usize> {
+    usize::try_from(read_u64(r)?).map_err(invalid)
+}
+
+fn read_str<R: Read>(r: &mut R) -> io::Result<String> {
+    let len = read_len(r)?;
+    let mut bytes = Vec::new();
+    r.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(bytes).map_err(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayon::ThreadPoolBuilder;
+
+    const STRINGS: [&str; 5] = [
+        "beniamino green",
+        "ben green",
+        "jack green",
+        "gibberish",
+        "jack greene",
+    ];
+
+    fn pool() -> ThreadPool {
+        ThreadPoolBuilder::new().num_threads(2).build().unwrap()
+    }
+
+    fn empty(scheme: HashScheme) -> LshIndex<u32> {
+        let config = ShingleConfig::new(vec![2], Tokenizer::Char).with_padding(true);
+        LshIndex::new(config, scheme, 20, 2, 1)
+    }
+
+    fn index(scheme: HashScheme) -> LshIndex<u32> {
+        let mut index = empty(scheme);
+        index.insert(&STRINGS, &pool()).unwrap();
+        index
+    }
+
+    fn query(index: &LshIndex<u32>) -> Vec<(usize, usize, f64)> {
+        let mut matches = index
+            .query(
+                &["beniamino green", "jack green"],
+                0.5,
+                &JoinDiagnostics::default(),
+                &pool(),
+            )
+            .unwrap();
+        matches.sort_by_key(|(query, record, _)| (*query, *record));
+        matches
+    }
+
+    fn saved(index: LshIndex<u32>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        MinHashIndex::U32(index).write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn load(bytes: &[u8]) -> io::Result<LshIndex<u32>> {
+        match MinHashIndex::read_from(&mut &bytes[..])? {
+            MinHashIndex::U32(index) => Ok(index),
+            MinHashIndex::U64(_) => panic!("the shingle id width changed on load"),
+        }
+    }
+
+    #[test]
+    fn saved_indexes_load_with_the_same_buckets() {
+        for scheme in [HashScheme::MinHash, HashScheme::OnePermutation] {
+            let expected = index(scheme);
+            let loaded = load(&saved(index(scheme))).unwrap();
+            assert_eq!(loaded.n_records(), STRINGS.len());
+            assert_eq!(loaded.tables, expected.tables);
+            assert_eq!(query(&loaded), query(&expected));
+            assert!(query(&loaded)
+                .iter()
+                .any(|(q, record, _)| (*q, *record) == (0, 0)));
+        }
+    }
+
+    #[test]
+    fn empty_indexes_round_trip() {
+        let loaded = load(&saved(empty(HashScheme::MinHash))).unwrap();
+        assert_eq!(loaded.n_records(), 0);
+        assert!(query(&loaded).is_empty());
+    }
+
+    #[test]
+    fn files_with_another_header_are_refused() {
+        let bytes = saved(index(HashScheme::MinHash));
+
+        let mut not_an_index = bytes.clone();
+        not_an_index[..8].copy_from_slice(b"NOTANIDX");
+        let error = load(&not_an_index).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "not a zoomerjoin index");
+
+        let mut old = bytes.clone();
+        old[8..12].copy_from_slice(&(VERSION - 1).to_le_bytes());
+        let error = load(&old).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            format!("unsupported index version {}", VERSION - 1)
+        );
+
+        let mut narrow = bytes;
+        narrow[12..16].copy_from_slice(&16u32.to_le_bytes());
+        let error = load(&narrow).err().unwrap();
+        assert_eq!(error.to_string(), "unsupported shingle id width 16");
+    }
+
+    #[test]
+    fn truncated_files_are_an_error() {
+        let bytes = saved(index(HashScheme::OnePermutation));
+        for len in 0..bytes.len() {
+            assert!(load(&bytes[..len]).is_err(), "truncated to {len} bytes");
+        }
+    }
+
+    #[test]
+    fn batches_get_consecutive_ids_and_the_buckets_of_one_insert() {
+        let mut batched = empty(HashScheme::MinHash);
+        assert_eq!(batched.insert(&STRINGS[..2], &pool()).unwrap(), 0..2);
+        assert_eq!(batched.insert(&[], &pool()).unwrap(), 2..2);
+        assert_eq!(batched.insert(&STRINGS[2..], &pool()).unwrap(), 2..5);
+
+        let whole = index(HashScheme::MinHash);
+        assert_eq!(batched.tables, whole.tables);
+        assert_eq!(query(&batched), query(&whole));
+
+        // Inserting after a load continues the ids where the file left off.
+        let mut loaded = load(&saved(batched)).unwrap();
+        assert_eq!(loaded.insert(&["jack green"], &pool()).unwrap(), 5..6);
+        assert!(query(&loaded)
+            .iter()
+            .any(|(q, record, _)| (*q, *record) == (1, 5)));
+    }
+}
@@ -0,0 +1,93 @@
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use extendr_api::throw_r_error;
+use rayon::ThreadPool;
+
+/// How often R is asked whether the user has interrupted.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Set once the user interrupts the running join. R calls into the package
+/// from one thread at a time, so one flag serves every join.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    // Defined in `entrypoint.c`.
+    fn zoomerjoin_interrupt_pending() -> c_int;
+}
+
+// Unit tests are not linked against `entrypoint.c` or R, so they get a
+// stand-in that never reports an interrupt.
+#[cfg(test)]
+mod stub {
+    #[no_mangle]
+    extern "C" fn zoomerjoin_interrupt_pending() -> std::os::raw::c_int {
+        0
+    }
+}
+
+/// Whether the running join has been interrupted. Parallel loops check this
+/// once per record and skip the rest of their work when it is set.
+#[inline]
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Returned in place of a result when the user interrupted the work.
+/// Functions that run under `install` pass it up with `?`, so that what
+/// they allocated is dropped on the way, and only the `#[extendr]` entry
+/// point turns it into an R error with `throw`. R raises errors with a
+/// `longjmp`, which runs no destructors, so throwing any deeper would leak
+/// the thread pool, signatures and tables of the frames it jumps over.
+#[derive(Debug, Clone, Copy)]
+pub struct Interrupted;
+
+impl Interrupted {
+    /// Raise the R error for the interrupt. Call this only from an
+    /// `#[extendr]` entry point, once everything the work held is dropped.
+    pub fn throw(self) -> ! {
+        throw_r_error("interrupted by the user")
+    }
+}
+
+/// Run `f` on `pool`, as `pool.install` does, while the calling thread
+/// checks for a user interrupt every `POLL_INTERVAL`. Rayon workers cannot
+/// call into R, and R cannot notice Ctrl-C or Escape while its main thread
+/// is blocked in `pool.install`, so `f` runs on a helper thread instead. On
+/// an interrupt, `interrupted()` tells the loops in `f` to wind down, and
+/// once they have, their partial result is dropped and `Interrupted` is
+/// returned in its place.
+///
+/// Must be called from R's main thread, and `f` must not call into R.
+pub fn install<F, R>(pool: &ThreadPool, f: F) -> Result<R, Interrupted>
+where
+    F: FnOnce() -> R + Send,
+    R: Send,
+{
+    INTERRUPTED.store(false, Ordering::Relaxed);
+
+    let out = thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        scope.spawn(move || sender.send(pool.install(f)));
+        loop {
+            match receiver.recv_timeout(POLL_INTERVAL) {
+                Ok(out) => return Some(out),
+                Err(RecvTimeoutError::Timeout) => {
+                    if !interrupted() && unsafe { zoomerjoin_interrupt_pending() } != 0 {
+                        INTERRUPTED.store(true, Ordering::Relaxed);
+                    }
+                }
+                // `f` panicked, which the scope passes on once it ends.
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    });
+
+    if interrupted() {
+        return Err(Interrupted);
+    }
+    Ok(out.expect("the join thread panicked"))
+}
@@ -3,9 +3,20 @@ use extendr_api::prelude::*;
 use ndarray::parallel::prelude::*;
 use ndarray::{Array2, ArrayView2, Axis};
 use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+// The prelude's `Result` is fixed to `extendr_api::Error`.
+use std::result::Result;
+use std::time::Instant;
+
+pub mod normalize;
+use crate::normalize::Normalizer;
+
+pub mod phonetic;
+use crate::phonetic::PhoneticEncoder;
 
 pub mod shingleset;
-use crate::shingleset::ShingleSet;
+use crate::shingleset::{ShingleConfig, ShingleId, ShingleSet, Tokenizer};
 
 pub mod em_link;
 use crate::em_link::EMLinker;
@@ -13,12 +24,26 @@ use crate::em_link::EMLinker;
 pub mod euclidianhasher;
 pub mod minihasher;
 use crate::euclidianhasher::EuclidianHasher;
+use crate::minihasher::{HashScheme, SignatureHasher};
+pub mod buckets;
+pub mod diagnostics;
+pub mod ensemble;
 pub mod minhashjoiner;
-use crate::minhashjoiner::MinHashJoiner;
+pub mod ppjoin;
+use crate::buckets::BucketLimit;
+use crate::diagnostics::{JoinDiagnostics, ProbeCounts};
+pub mod signatures;
+use crate::minhashjoiner::{MinHashJoiner, Records};
+use crate::signatures::{band_join, Signatures};
 
 pub mod hamminghasher;
 use crate::hamminghasher::HammingHasher;
 
+pub mod index;
+pub mod interrupt;
+use crate::index::{LshIndex, MinHashIndex};
+use crate::interrupt::{interrupted, Interrupted};
+
 use rand::rngs::StdRng;
 use rand::SeedableRng;
 
@@ -34,40 +59,62 @@ fn rust_em_link(x_robj: Robj, probs: &[f64], tol: f64, max_iter: i32) -> Vec<f64
 }
 
 #[extendr]
+#[allow(clippy::too_many_arguments)]
 fn rust_jaccard_similarity(
     left_string_r: Robj,
     right_string_r: Robj,
-    ngram_width: i64,
+    ngram_width: Robj,
+    tokenizer: &str,
+    normalize: Robj,
+    phonetic: Robj,
+    pad: bool,
+    multiset: bool,
+    shingle_bits: i32,
     nthread: Option<usize>,
 ) -> Doubles {
+    let config = shingle_config(ngram_width, tokenizer, normalize, phonetic, pad, multiset);
+    check_shingle_bits(shingle_bits);
     let pool = get_pool(nthread);
 
     let right_string_vec = right_string_r.as_str_vector().unwrap();
     let left_string_vec = left_string_r.as_str_vector().unwrap();
 
+    let out_vec = match shingle_bits {
+        32 => jaccard_similarities::<u32>(&left_string_vec, &right_string_vec, &config, &pool),
+        64 => jaccard_similarities::<u64>(&left_string_vec, &right_string_vec, &config, &pool),
+        _ => unreachable!("'shingle_bits' is checked before the pool is built"),
+    };
+
+    out_vec
+        .into_iter()
+        .map(|i| Rfloat::from(i))
+        .collect::<Doubles>()
+}
+
+fn jaccard_similarities<T: ShingleId>(
+    left_string_vec: &[&str],
+    right_string_vec: &[&str],
+    config: &ShingleConfig,
+    pool: &ThreadPool,
+) -> Vec<f64> {
     // vector to hold sets of n_gram strings in each document
-    let out_vec = pool.install(|| {
-        let left_set_vec: Vec<ShingleSet> = left_string_vec
+    pool.install(|| {
+        let left_set_vec: Vec<ShingleSet<T>> = left_string_vec
             .par_iter()
             .enumerate()
-            .map(|(i, x)| ShingleSet::new(x, ngram_width as usize, i, None))
+            .map(|(i, x)| ShingleSet::new(x, config, i, None))
             .collect();
-        let right_set_vec: Vec<ShingleSet> = right_string_vec
+        let right_set_vec: Vec<ShingleSet<T>> = right_string_vec
             .par_iter()
             .enumerate()
-            .map(|(i, x)| ShingleSet::new(x, ngram_width as usize, i, None))
+            .map(|(i, x)| ShingleSet::new(x, config, i, None))
             .collect();
         left_set_vec
             .into_par_iter()
             .zip(right_set_vec)
             .map(|(a, b)| a.jaccard_similarity(&b))
             .collect::<Vec<f64>>()
-    });
-
-    out_vec
-        .into_iter()
-        .map(|i| Rfloat::from(i))
-        .collect::<Doubles>()
+    })
 }
 
 #[extendr]
@@ -103,129 +150,968 @@ fn rust_hamming_distance(
 }
 
 #[extendr]
+fn rust_phonetic_encode(string_r: Robj, encoder: &str, nthread: Option<usize>) -> Vec<String> {
+    let encoder: PhoneticEncoder = encoder.parse().unwrap_or_else(|e: String| throw_r_error(e));
+    let pool = get_pool(nthread);
+
+    let string_vec = string_r.as_str_vector().unwrap();
+
+    pool.install(|| {
+        string_vec
+            .par_iter()
+            .map(|string| encoder.encode(string))
+            .collect()
+    })
+}
+
+#[extendr]
+#[allow(clippy::too_many_arguments)]
 fn rust_jaccard_join(
     left_string_r: Robj,
     right_string_r: Robj,
-    ngram_width: i64,
+    ngram_width: Robj,
+    tokenizer: &str,
+    normalize: Robj,
+    phonetic: Robj,
+    pad: bool,
+    multiset: bool,
+    shingle_bits: i32,
     n_bands: i64,
     band_size: i64,
     threshold: f64,
+    hasher: &str,
+    weighted: bool,
+    estimate: bool,
+    exact: bool,
+    candidates: bool,
+    top_k: Option<usize>,
+    n_probes: usize,
+    max_bucket_size: Option<usize>,
     progress: bool,
     seed: u64,
     nthread: Option<usize>,
 ) -> Robj {
-    let pool = get_pool(nthread);
+    let config = shingle_config(ngram_width, tokenizer, normalize, phonetic, pad, multiset);
+    let scheme = hash_scheme(hasher, weighted);
+    check_shingle_bits(shingle_bits);
+    check_join_modes(
+        scheme, weighted, estimate, exact, candidates, top_k, n_probes, threshold,
+    );
 
-    let right_string_vec = right_string_r.as_str_vector().unwrap();
-    let left_string_vec = left_string_r.as_str_vector().unwrap();
+    let result = {
+        let pool = get_pool(nthread);
 
-    if progress {
-        rprintln!("Starting to generate shingles");
-    }
+        let right_string_vec = right_string_r.as_str_vector().unwrap();
+        let left_string_vec = left_string_r.as_str_vector().unwrap();
+
+        if progress {
+            rprintln!("Starting to generate shingles");
+        }
+
+        match shingle_bits {
+            32 => jaccard_join::<u32>(
+                left_string_vec,
+                right_string_vec,
+                None,
+                &config,
+                weighted,
+                estimate,
+                exact,
+                candidates,
+                top_k,
+                n_probes,
+                max_bucket_size,
+                scheme,
+                n_bands,
+                band_size,
+                threshold,
+                progress,
+                seed,
+                &pool,
+            ),
+            64 => jaccard_join::<u64>(
+                left_string_vec,
+                right_string_vec,
+                None,
+                &config,
+                weighted,
+                estimate,
+                exact,
+                candidates,
+                top_k,
+                n_probes,
+                max_bucket_size,
+                scheme,
+                n_bands,
+                band_size,
+                threshold,
+                progress,
+                seed,
+                &pool,
+            ),
+            _ => unreachable!("'shingle_bits' is checked before the pool is built"),
+        }
+    };
+    result.unwrap_or_else(|interrupted| interrupted.throw())
+}
 
-    let joiner = MinHashJoiner::new(
-        left_string_vec,
-        right_string_vec,
-        ngram_width as usize,
-        &pool,
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn rust_salted_jaccard_join(
+    left_string_r: Robj,
+    right_string_r: Robj,
+    left_salt_r: Robj,
+    right_salt_r: Robj,
+    ngram_width: Robj,
+    tokenizer: &str,
+    normalize: Robj,
+    phonetic: Robj,
+    pad: bool,
+    multiset: bool,
+    shingle_bits: i32,
+    n_bands: i64,
+    band_size: i64,
+    threshold: f64,
+    hasher: &str,
+    weighted: bool,
+    estimate: bool,
+    exact: bool,
+    candidates: bool,
+    top_k: Option<usize>,
+    n_probes: usize,
+    max_bucket_size: Option<usize>,
+    progress: bool,
+    seed: u64,
+    nthread: Option<usize>,
+) -> Robj {
+    let config = shingle_config(ngram_width, tokenizer, normalize, phonetic, pad, multiset);
+    let scheme = hash_scheme(hasher, weighted);
+    check_shingle_bits(shingle_bits);
+    check_join_modes(
+        scheme, weighted, estimate, exact, candidates, top_k, n_probes, threshold,
     );
 
+    let result = {
+        let pool = get_pool(nthread);
+
+        let left_string_vec = left_string_r.as_str_vector().unwrap();
+        let right_string_vec = right_string_r.as_str_vector().unwrap();
+
+        let salts = Some((
+            left_salt_r.as_str_vector().unwrap(),
+            right_salt_r.as_str_vector().unwrap(),
+        ));
+
+        if progress {
+            rprintln!("Starting to generate shingles");
+        }
+
+        match shingle_bits {
+            32 => jaccard_join::<u32>(
+                left_string_vec,
+                right_string_vec,
+                salts,
+                &config,
+                weighted,
+                estimate,
+                exact,
+                candidates,
+                top_k,
+                n_probes,
+                max_bucket_size,
+                scheme,
+                n_bands,
+                band_size,
+                threshold,
+                progress,
+                seed,
+                &pool,
+            ),
+            64 => jaccard_join::<u64>(
+                left_string_vec,
+                right_string_vec,
+                salts,
+                &config,
+                weighted,
+                estimate,
+                exact,
+                candidates,
+                top_k,
+                n_probes,
+                max_bucket_size,
+                scheme,
+                n_bands,
+                band_size,
+                threshold,
+                progress,
+                seed,
+                &pool,
+            ),
+            _ => unreachable!("'shingle_bits' is checked before the pool is built"),
+        }
+    };
+    result.unwrap_or_else(|interrupted| interrupted.throw())
+}
+
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn rust_jaccard_self_join(
+    string_r: Robj,
+    ngram_width: Robj,
+    tokenizer: &str,
+    normalize: Robj,
+    phonetic: Robj,
+    pad: bool,
+    multiset: bool,
+    shingle_bits: i32,
+    n_bands: i64,
+    band_size: i64,
+    threshold: f64,
+    hasher: &str,
+    max_bucket_size: Option<usize>,
+    progress: bool,
+    seed: u64,
+    nthread: Option<usize>,
+) -> Robj {
+    let config = shingle_config(ngram_width, tokenizer, normalize, phonetic, pad, multiset);
+    let scheme = hash_scheme(hasher, false);
+    check_shingle_bits(shingle_bits);
+
+    let result = {
+        let pool = get_pool(nthread);
+
+        let string_vec = string_r.as_str_vector().unwrap();
+
+        if progress {
+            rprintln!("Starting to generate shingles");
+        }
+
+        match shingle_bits {
+            32 => MinHashJoiner::<u32>::new_self(string_vec, &config, &pool).and_then(|joiner| {
+                jaccard_self_join(
+                    joiner,
+                    n_bands as usize,
+                    band_size as usize,
+                    threshold,
+                    scheme,
+                    max_bucket_size,
+                    progress,
+                    seed,
+                    &pool,
+                )
+            }),
+            64 => MinHashJoiner::<u64>::new_self(string_vec, &config, &pool).and_then(|joiner| {
+                jaccard_self_join(
+                    joiner,
+                    n_bands as usize,
+                    band_size as usize,
+                    threshold,
+                    scheme,
+                    max_bucket_size,
+                    progress,
+                    seed,
+                    &pool,
+                )
+            }),
+            _ => unreachable!("'shingle_bits' is checked before the pool is built"),
+        }
+    };
+    result.unwrap_or_else(|interrupted| interrupted.throw())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn jaccard_self_join<T: ShingleId>(
+    joiner: MinHashJoiner<T>,
+    n_bands: usize,
+    band_size: usize,
+    threshold: f64,
+    scheme: HashScheme,
+    max_bucket_size: Option<usize>,
+    progress: bool,
+    seed: u64,
+    pool: &ThreadPool,
+) -> Result<Robj, Interrupted> {
     if progress {
-        rprintln!("Done generating shingles");
+        report_shingle_collisions(&joiner, pool);
     }
+    let joiner = joiner.with_max_bucket_size(max_bucket_size);
+    let chosen_indexes =
+        joiner.self_join(n_bands, band_size, threshold, scheme, progress, seed, pool)?;
 
-    let chosen_indexes = joiner.join(
-        n_bands as usize,
-        band_size as usize,
-        threshold,
-        progress,
-        seed,
-        &pool,
-    );
-
-    let mut out_arr: Array2<u64> = Array2::zeros((chosen_indexes.len(), 2));
-    for (i, pair) in chosen_indexes.iter().enumerate() {
-        out_arr[[i, 0]] = pair.1 as u64 + 1;
-        out_arr[[i, 1]] = pair.0 as u64 + 1;
+    let mut out_arr: Array2<f64> = Array2::zeros((chosen_indexes.len(), 3));
+    for (i, ((first, second), similarity)) in chosen_indexes.into_iter().enumerate() {
+        out_arr[[i, 0]] = first as f64 + 1.0;
+        out_arr[[i, 1]] = second as f64 + 1.0;
+        out_arr[[i, 2]] = similarity;
     }
 
-    Robj::try_from(&out_arr).into()
+    Ok(with_diagnostics(
+        with_dropped_buckets(Robj::try_from(&out_arr).into(), joiner.bucket_limit()),
+        joiner.diagnostics(),
+    ))
 }
 
+/// Containment join of the left strings into the right strings. Salts are
+/// `NULL` for an unblocked join.
 #[extendr]
-fn rust_salted_jaccard_join(
+#[allow(clippy::too_many_arguments)]
+fn rust_containment_join(
     left_string_r: Robj,
     right_string_r: Robj,
     left_salt_r: Robj,
     right_salt_r: Robj,
-    ngram_width: i64,
+    ngram_width: Robj,
+    tokenizer: &str,
+    normalize: Robj,
+    phonetic: Robj,
+    pad: bool,
+    multiset: bool,
+    shingle_bits: i32,
     n_bands: i64,
     band_size: i64,
+    n_partitions: i64,
     threshold: f64,
     progress: bool,
     seed: u64,
     nthread: Option<usize>,
 ) -> Robj {
-    let pool = get_pool(nthread);
+    let config = shingle_config(ngram_width, tokenizer, normalize, phonetic, pad, multiset);
+    check_shingle_bits(shingle_bits);
+    if n_partitions < 1 {
+        throw_r_error("'n_partitions' must be at least 1");
+    }
 
-    let left_string_vec = left_string_r.as_str_vector().unwrap();
-    let right_string_vec = right_string_r.as_str_vector().unwrap();
+    let result = {
+        let pool = get_pool(nthread);
 
-    let right_salt_vec = right_salt_r.as_str_vector().unwrap();
-    let left_salt_vec = left_salt_r.as_str_vector().unwrap();
+        let left_string_vec = left_string_r.as_str_vector().unwrap();
+        let right_string_vec = right_string_r.as_str_vector().unwrap();
+        let salts = if left_salt_r.is_null() || right_salt_r.is_null() {
+            None
+        } else {
+            Some((
+                left_salt_r.as_str_vector().unwrap(),
+                right_salt_r.as_str_vector().unwrap(),
+            ))
+        };
 
+        if progress {
+            rprintln!("Starting to generate shingles");
+        }
+
+        match shingle_bits {
+            32 => containment_joiner::<u32>(
+                left_string_vec,
+                right_string_vec,
+                salts,
+                &config,
+                &pool,
+                progress,
+            )
+            .and_then(|joiner| {
+                let matches = joiner.containment_join(
+                    n_bands as usize,
+                    band_size as usize,
+                    n_partitions as usize,
+                    threshold,
+                    progress,
+                    seed,
+                    &pool,
+                )?;
+                Ok(containment_table(matches, joiner.diagnostics()))
+            }),
+            64 => containment_joiner::<u64>(
+                left_string_vec,
+                right_string_vec,
+                salts,
+                &config,
+                &pool,
+                progress,
+            )
+            .and_then(|joiner| {
+                let matches = joiner.containment_join(
+                    n_bands as usize,
+                    band_size as usize,
+                    n_partitions as usize,
+                    threshold,
+                    progress,
+                    seed,
+                    &pool,
+                )?;
+                Ok(containment_table(matches, joiner.diagnostics()))
+            }),
+            _ => unreachable!("'shingle_bits' is checked before the pool is built"),
+        }
+    };
+    result.unwrap_or_else(|interrupted| interrupted.throw())
+}
+
+/// The output of `rust_containment_join`: the `(left, right, containment)`
+/// `matches` as 1-based rows, with their `diagnostics` attached.
+fn containment_table(matches: Vec<(usize, usize, f64)>, diagnostics: &JoinDiagnostics) -> Robj {
+    let mut out_arr: Array2<f64> = Array2::zeros((matches.len(), 3));
+    for (i, (left, right, containment)) in matches.into_iter().enumerate() {
+        out_arr[[i, 0]] = left as f64 + 1.0;
+        out_arr[[i, 1]] = right as f64 + 1.0;
+        out_arr[[i, 2]] = containment;
+    }
+
+    with_diagnostics(Robj::try_from(&out_arr).into(), diagnostics)
+}
+
+fn containment_joiner<'a, T: ShingleId>(
+    left: Vec<&'a str>,
+    right: Vec<&'a str>,
+    salts: Option<(Vec<&'a str>, Vec<&'a str>)>,
+    config: &ShingleConfig,
+    pool: &ThreadPool,
+    progress: bool,
+) -> Result<MinHashJoiner<T>, Interrupted> {
+    let joiner: MinHashJoiner<T> = match salts {
+        Some((left_salt, right_salt)) => {
+            MinHashJoiner::new_with_salt(left, right, left_salt, right_salt, config, pool)?
+        }
+        None => MinHashJoiner::new(left, right, config, pool)?,
+    };
     if progress {
-        rprintln!("Starting to generate shingles");
+        report_shingle_collisions(&joiner, pool);
     }
+    Ok(joiner)
+}
 
-    let joiner = MinHashJoiner::new_with_salt(
-        left_string_vec,
-        right_string_vec,
-        left_salt_vec,
-        right_salt_vec,
-        ngram_width as usize,
-        &pool,
-    );
+/// The output of the Jaccard joins of `left` into `right`, blocked by
+/// `salts` if given. Joins that estimate similarities sign each record as
+/// it is shingled and never hold the shingle sets; the others shingle both
+/// sides up front.
+#[allow(clippy::too_many_arguments)]
+fn jaccard_join<T: ShingleId>(
+    left: Vec<&str>,
+    right: Vec<&str>,
+    salts: Option<(Vec<&str>, Vec<&str>)>,
+    config: &ShingleConfig,
+    weighted: bool,
+    estimate: bool,
+    exact: bool,
+    candidates: bool,
+    top_k: Option<usize>,
+    n_probes: usize,
+    max_bucket_size: Option<usize>,
+    scheme: HashScheme,
+    n_bands: i64,
+    band_size: i64,
+    threshold: f64,
+    progress: bool,
+    seed: u64,
+    pool: &ThreadPool,
+) -> Result<Robj, Interrupted> {
+    if estimate {
+        let (left_salt, right_salt) = salts.unzip();
+        let left = Records::new(left, left_salt);
+        let right = Records::new(right, right_salt);
+        let joiner = MinHashJoiner::<T>::unshingled();
+        let joiner = if weighted {
+            joiner.with_record_idf_weights(&left, &right, config, pool)?
+        } else {
+            joiner
+        }
+        .with_multi_probe(n_probes)
+        .with_max_bucket_size(max_bucket_size);
+
+        let matches = joiner.estimate_join(
+            &left,
+            &right,
+            config,
+            n_bands as usize,
+            band_size as usize,
+            threshold,
+            scheme,
+            progress,
+            seed,
+            pool,
+        )?;
 
+        let mut out_arr: Array2<f64> = Array2::zeros((matches.len(), 4));
+        for (i, (larger, smaller, similarity, std_error)) in matches.into_iter().enumerate() {
+            out_arr[[i, 0]] = smaller as f64 + 1.0;
+            out_arr[[i, 1]] = larger as f64 + 1.0;
+            out_arr[[i, 2]] = similarity;
+            out_arr[[i, 3]] = std_error;
+        }
+
+        return Ok(with_diagnostics(
+            with_dropped_buckets(Robj::try_from(&out_arr).into(), joiner.bucket_limit()),
+            joiner.diagnostics(),
+        ));
+    }
+
+    let joiner: MinHashJoiner<T> = match salts {
+        Some((left_salt, right_salt)) => {
+            MinHashJoiner::new_with_salt(left, right, left_salt, right_salt, config, pool)?
+        }
+        None => MinHashJoiner::new(left, right, config, pool)?,
+    };
     if progress {
         rprintln!("Done generating shingles");
+        report_shingle_collisions(&joiner, pool);
+    }
+
+    let joiner = if weighted {
+        joiner.with_idf_weights(pool)
+    } else {
+        joiner
+    }
+    .with_multi_probe(n_probes)
+    .with_max_bucket_size(max_bucket_size);
+
+    if candidates {
+        let collisions = joiner.candidate_join(
+            n_bands as usize,
+            band_size as usize,
+            scheme,
+            progress,
+            seed,
+            pool,
+        )?;
+
+        let mut out_arr: Array2<f64> = Array2::zeros((collisions.len(), 3));
+        for (i, ((larger, smaller), n_collisions)) in collisions.into_iter().enumerate() {
+            out_arr[[i, 0]] = smaller as f64 + 1.0;
+            out_arr[[i, 1]] = larger as f64 + 1.0;
+            out_arr[[i, 2]] = n_collisions as f64;
+        }
+
+        return Ok(with_diagnostics(
+            with_dropped_buckets(Robj::try_from(&out_arr).into(), joiner.bucket_limit()),
+            joiner.diagnostics(),
+        ));
+    }
+
+    if exact {
+        let matches = joiner.exact_join(threshold, progress, pool)?;
+
+        let mut out_arr: Array2<f64> = Array2::zeros((matches.len(), 3));
+        for (i, (left, right, similarity)) in matches.into_iter().enumerate() {
+            out_arr[[i, 0]] = left as f64 + 1.0;
+            out_arr[[i, 1]] = right as f64 + 1.0;
+            out_arr[[i, 2]] = similarity;
+        }
+
+        return Ok(Robj::try_from(&out_arr).into());
+    }
+
+    if let Some(k) = top_k {
+        let matches = joiner.top_k_join(
+            k,
+            n_bands as usize,
+            band_size as usize,
+            threshold,
+            scheme,
+            progress,
+            seed,
+            pool,
+        )?;
+
+        let mut out_arr: Array2<f64> = Array2::zeros((matches.len(), 3));
+        for (i, (left, right, similarity)) in matches.into_iter().enumerate() {
+            out_arr[[i, 0]] = left as f64 + 1.0;
+            out_arr[[i, 1]] = right as f64 + 1.0;
+            out_arr[[i, 2]] = similarity;
+        }
+
+        return Ok(with_diagnostics(
+            with_dropped_buckets(Robj::try_from(&out_arr).into(), joiner.bucket_limit()),
+            joiner.diagnostics(),
+        ));
     }
 
     let chosen_indexes = joiner.join(
         n_bands as usize,
         band_size as usize,
         threshold,
+        scheme,
         progress,
         seed,
-        &pool,
-    );
+        pool,
+    )?;
 
-    let mut out_arr: Array2<u64> = Array2::zeros((chosen_indexes.len(), 2));
-    for (i, pair) in chosen_indexes.iter().enumerate() {
-        out_arr[[i, 0]] = pair.1 as u64 + 1;
-        out_arr[[i, 1]] = pair.0 as u64 + 1;
+    let mut out_arr: Array2<f64> = Array2::zeros((chosen_indexes.len(), 3));
+    for (i, ((larger, smaller), similarity)) in chosen_indexes.into_iter().enumerate() {
+        out_arr[[i, 0]] = smaller as f64 + 1.0;
+        out_arr[[i, 1]] = larger as f64 + 1.0;
+        out_arr[[i, 2]] = similarity;
     }
 
-    Robj::try_from(&out_arr).into()
+    Ok(with_diagnostics(
+        with_dropped_buckets(Robj::try_from(&out_arr).into(), joiner.bucket_limit()),
+        joiner.diagnostics(),
+    ))
 }
 
+/// Attach the number of buckets and candidate pairs `limit` skipped to the
+/// output of a join, for the R side to warn about.
+fn with_dropped_buckets(mut out: Robj, limit: &BucketLimit) -> Robj {
+    out.set_attrib("dropped_buckets", limit.dropped_buckets() as f64)
+        .and_then(|out| out.set_attrib("dropped_pairs", limit.dropped_pairs() as f64))
+        .unwrap_or_else(|e| throw_r_error(e.to_string()));
+    out
+}
+
+/// Attach the per-band `diagnostics` of a join to its output, as a matrix
+/// with one row per band.
+fn with_diagnostics(mut out: Robj, diagnostics: &JoinDiagnostics) -> Robj {
+    let bands: Robj = Robj::try_from(&diagnostics.to_matrix()).into();
+    out.set_attrib("diagnostics", bands)
+        .unwrap_or_else(|e| throw_r_error(e.to_string()));
+    out
+}
+
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn rust_jaccard_signatures(
+    string_r: Robj,
+    ngram_width: Robj,
+    tokenizer: &str,
+    normalize: Robj,
+    phonetic: Robj,
+    pad: bool,
+    multiset: bool,
+    shingle_bits: i32,
+    n_bands: i64,
+    band_size: i64,
+    bits: i32,
+    hasher: &str,
+    seed: u64,
+    nthread: Option<usize>,
+) -> Robj {
+    let config = shingle_config(ngram_width, tokenizer, normalize, phonetic, pad, multiset);
+    let scheme = hash_scheme(hasher, false);
+    let bits = signature_bits(bits);
+    check_shingle_bits(shingle_bits);
+
+    let result = {
+        let pool = get_pool(nthread);
+
+        let string_vec = string_r.as_str_vector().unwrap();
+        let len = (n_bands * band_size) as usize;
+
+        let signatures = match shingle_bits {
+            32 => string_signatures::<u32>(&string_vec, &config, scheme, len, bits, seed, &pool),
+            64 => string_signatures::<u64>(&string_vec, &config, scheme, len, bits, seed, &pool),
+            _ => unreachable!("'shingle_bits' is checked before the pool is built"),
+        };
+
+        // Full signatures go back as a numeric matrix; b-bit signatures go back
+        // packed, one record after another, and are reshaped in R.
+        signatures.map(|signatures| {
+            if bits == 32 {
+                Robj::try_from(&signatures.to_matrix()).into()
+            } else {
+                Raw::from_bytes(signatures.packed()).into()
+            }
+        })
+    };
+    result.unwrap_or_else(|interrupted| interrupted.throw())
+}
+
+/// Signatures of `strings`, drawn exactly as `MinHashJoiner::join` draws
+/// them for the same seed, so that stored signatures can be joined against
+/// signatures computed later.
+fn string_signatures<T: ShingleId>(
+    strings: &[&str],
+    config: &ShingleConfig,
+    scheme: HashScheme,
+    len: usize,
+    bits: u32,
+    seed: u64,
+    pool: &ThreadPool,
+) -> Result<Signatures, Interrupted> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let hasher = SignatureHasher::new(scheme, len, &mut rng);
+
+    let shingle_sets: Vec<ShingleSet<T>> = interrupt::install(pool, || {
+        strings
+            .par_iter()
+            .enumerate()
+            .filter(|_| !interrupted())
+            .map(|(i, x)| ShingleSet::new(x, config, i, None))
+            .collect()
+    })?;
+
+    Signatures::compute(shingle_sets.len(), len, bits, pool, |i, out| {
+        hasher.signature(&shingle_sets[i], out)
+    })
+}
+
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn rust_signature_join(
+    left_signature_r: Robj,
+    right_signature_r: Robj,
+    n_bands: i64,
+    band_size: i64,
+    bits: i32,
+    threshold: f64,
+    progress: bool,
+    nthread: Option<usize>,
+) -> Robj {
+    let bits = signature_bits(bits);
+    let len = (n_bands * band_size) as usize;
+    // Decoded before the pool is built, so that a malformed matrix is
+    // reported with nothing allocated yet.
+    let signatures = read_signatures(&left_signature_r, len, bits)
+        .and_then(|left| Ok((left, read_signatures(&right_signature_r, len, bits)?)));
+
+    let result = {
+        let (left, right) = signatures.unwrap_or_else(|e| throw_r_error(e));
+        let pool = get_pool(nthread);
+
+        let diagnostics = JoinDiagnostics::default();
+        let chosen_indexes = band_join(
+            &left,
+            &right,
+            None,
+            &BucketLimit::default(),
+            &diagnostics,
+            n_bands as usize,
+            band_size as usize,
+            threshold,
+            progress,
+            &pool,
+            |r, l| right.estimate(r, &left, l),
+        );
+
+        chosen_indexes.map(|chosen_indexes| {
+            let mut out_arr: Array2<f64> = Array2::zeros((chosen_indexes.len(), 4));
+            for (i, ((r, l), similarity)) in chosen_indexes.into_iter().enumerate() {
+                out_arr[[i, 0]] = l as f64 + 1.0;
+                out_arr[[i, 1]] = r as f64 + 1.0;
+                out_arr[[i, 2]] = similarity;
+                out_arr[[i, 3]] = right.standard_error(similarity);
+            }
+
+            with_diagnostics(Robj::try_from(&out_arr).into(), &diagnostics)
+        })
+    };
+    result.unwrap_or_else(|interrupted| interrupted.throw())
+}
+
+/// Signatures of length `len` and `bits` bits stored in `signature_r`, as
+/// returned by `rust_jaccard_signatures`.
+fn read_signatures(signature_r: &Robj, len: usize, bits: u32) -> Result<Signatures, String> {
+    let signatures = if bits == 32 {
+        let matrix = <ArrayView2<f64>>::try_from(signature_r)
+            .map_err(|_| "32-bit signatures must be a double matrix".to_string())?;
+        Signatures::from_matrix(matrix)?
+    } else {
+        let bytes = signature_r
+            .as_raw_slice()
+            .ok_or_else(|| "packed signatures must be a raw matrix".to_string())?;
+        Signatures::from_packed(bytes, len, bits)?
+    };
+
+    if signatures.len() != len {
+        return Err("signature matrices must have n_bands * band_width columns".to_string());
+    }
+    Ok(signatures)
+}
+
+/// A MinHash LSH index over one set of strings, which later batches of
+/// strings can be queried against and which can be saved to disk.
+#[extendr]
+pub struct JaccardIndex(MinHashIndex);
+
 #[extendr]
+impl JaccardIndex {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        string_r: Robj,
+        ngram_width: Robj,
+        tokenizer: &str,
+        normalize: Robj,
+        phonetic: Robj,
+        pad: bool,
+        multiset: bool,
+        shingle_bits: i32,
+        n_bands: i64,
+        band_size: i64,
+        hasher: &str,
+        seed: u64,
+        nthread: Option<usize>,
+    ) -> Self {
+        let config = shingle_config(ngram_width, tokenizer, normalize, phonetic, pad, multiset);
+        let scheme = hash_scheme(hasher, false);
+        check_shingle_bits(shingle_bits);
+
+        let index = {
+            let pool = get_pool(nthread);
+            let (n_bands, band_size) = (n_bands as usize, band_size as usize);
+
+            let mut index = match shingle_bits {
+                32 => MinHashIndex::U32(LshIndex::new(config, scheme, n_bands, band_size, seed)),
+                64 => MinHashIndex::U64(LshIndex::new(config, scheme, n_bands, band_size, seed)),
+                _ => unreachable!("'shingle_bits' is checked before the pool is built"),
+            };
+            index
+                .insert(&string_r.as_str_vector().unwrap(), &pool)
+                .map(|_| index)
+        };
+        index
+            .map(Self)
+            .unwrap_or_else(|interrupted| interrupted.throw())
+    }
+
+    fn n_records(&self) -> f64 {
+        self.0.n_records() as f64
+    }
+
+    /// Add strings to the index. Returns the ids they were given, 1-based;
+    /// ids already handed out never change.
+    fn insert(&mut self, string_r: Robj, nthread: Option<usize>) -> Vec<f64> {
+        let ids = {
+            let pool = get_pool(nthread);
+            self.0.insert(&string_r.as_str_vector().unwrap(), &pool)
+        };
+        ids.unwrap_or_else(|interrupted| interrupted.throw())
+            .map(|id| id as f64 + 1.0)
+            .collect()
+    }
+
+    /// Returns a matrix of (query, id, similarity) rows, 1-based.
+    fn query(&self, string_r: Robj, threshold: f64, nthread: Option<usize>) -> Robj {
+        let result = {
+            let pool = get_pool(nthread);
+            let diagnostics = JoinDiagnostics::default();
+            let matches = self.0.query(
+                &string_r.as_str_vector().unwrap(),
+                threshold,
+                &diagnostics,
+                &pool,
+            );
+
+            matches.map(|matches| {
+                let mut out_arr: Array2<f64> = Array2::zeros((matches.len(), 3));
+                for (i, (query, id, similarity)) in matches.into_iter().enumerate() {
+                    out_arr[[i, 0]] = query as f64 + 1.0;
+                    out_arr[[i, 1]] = id as f64 + 1.0;
+                    out_arr[[i, 2]] = similarity;
+                }
+
+                with_diagnostics(Robj::try_from(&out_arr).into(), &diagnostics)
+            })
+        };
+        result.unwrap_or_else(|interrupted| interrupted.throw())
+    }
+
+    fn save(&self, path: &str) {
+        let result = File::create(path).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            self.0.write_to(&mut writer)?;
+            writer.flush()
+        });
+        if let Err(e) = result {
+            throw_r_error(format!("could not save index to '{path}': {e}"));
+        }
+    }
+
+    fn load(path: &str) -> Self {
+        File::open(path)
+            .and_then(|file| MinHashIndex::read_from(&mut BufReader::new(file)))
+            .map(Self)
+            .unwrap_or_else(|e| throw_r_error(format!("could not load index from '{path}': {e}")))
+    }
+}
+
+/// Print how many distinct n-grams the inputs contain and how many of them
+/// are lost to collisions under the chosen shingle id width.
+fn report_shingle_collisions<T: ShingleId>(joiner: &MinHashJoiner<T>, pool: &ThreadPool) {
+    let (n_ids, n_ids_32) = joiner.distinct_shingles(pool);
+
+    if T::BITS == 32 {
+        // Only the ids survive shingling, so the vocabulary is estimated from
+        // how many of the 2^32 ids it occupies.
+        let n_slots = 2f64.powi(32);
+        let n_shingles = -n_slots * (1.0 - n_ids_32 as f64 / n_slots).ln();
+        let lost = if n_shingles > 0.0 {
+            100.0 * (1.0 - n_ids_32 as f64 / n_shingles)
+        } else {
+            0.0
+        };
+        rprintln!("Shingle vocabulary: about {n_shingles:.0} distinct n-grams");
+        rprintln!(
+            "32-bit shingle ids: {n_ids_32} distinct, about {lost:.4}% of n-grams lost to collisions"
+        );
+    } else {
+        // Expected number of colliding pairs under 64-bit ids (birthday bound).
+        let expected = (n_ids as f64).powi(2) / 2f64.powi(65);
+        let lost = if n_ids > 0 {
+            100.0 * (1.0 - n_ids_32 as f64 / n_ids as f64)
+        } else {
+            0.0
+        };
+        rprintln!("Shingle vocabulary: {n_ids} distinct n-grams");
+        rprintln!(
+            "64-bit shingle ids: about {expected:.2e} expected collisions \
+             (32-bit ids would lose {lost:.4}% of n-grams)"
+        );
+    }
+}
+
+#[extendr]
+#[allow(clippy::too_many_arguments)]
 fn rust_hamming_join(
     left_string_r: Robj,
     right_string_r: Robj,
     band_width: u64,
     n_bands: u64,
     radius: u64,
+    max_bucket_size: Option<usize>,
+    candidates: bool,
     progress: bool,
     seed: u64,
     nthread: Option<usize>,
 ) -> Robj {
-    let pool = get_pool(nthread);
-    let left_string_vec = left_string_r.as_str_vector().unwrap();
-    let right_string_vec = right_string_r.as_str_vector().unwrap();
+    let result = {
+        let pool = get_pool(nthread);
+        let left_string_vec = left_string_r.as_str_vector().unwrap();
+        let right_string_vec = right_string_r.as_str_vector().unwrap();
 
+        hamming_join(
+            &left_string_vec,
+            &right_string_vec,
+            band_width,
+            n_bands,
+            radius,
+            max_bucket_size,
+            candidates,
+            progress,
+            seed,
+            &pool,
+        )
+    };
+    result.unwrap_or_else(|interrupted| interrupted.throw())
+}
+
+/// The strings of `right_string_vec` within Hamming distance `radius` of
+/// those of `left_string_vec`, found band by band, as the output of
+/// `rust_hamming_join`.
+#[allow(clippy::too_many_arguments)]
+fn hamming_join(
+    left_string_vec: &[&str],
+    right_string_vec: &[&str],
+    band_width: u64,
+    n_bands: u64,
+    radius: u64,
+    max_bucket_size: Option<usize>,
+    candidates: bool,
+    progress: bool,
+    seed: u64,
+    pool: &ThreadPool,
+) -> Result<Robj, Interrupted> {
     let pairs: DashSet<(usize, usize)> = DashSet::new();
+    let collisions: DashMap<(usize, usize), u64> = DashMap::new();
     let store: DashMap<u64, Vec<usize>> = DashMap::new();
+    let limit = BucketLimit::new(max_bucket_size);
+    let diagnostics = JoinDiagnostics::default();
 
     let max_size = left_string_vec
         .iter()
@@ -241,8 +1127,9 @@ fn rust_hamming_join(
         if progress {
             rprintln!("starting band {i} out of {n_bands}");
         }
+        let started = Instant::now();
 
-        pool.install(|| {
+        interrupt::install(pool, || {
             left_string_vec.par_iter().enumerate().for_each(|(i, x)| {
                 let hash = hasher.hash(x);
 
@@ -251,12 +1138,33 @@ fn rust_hamming_join(
                     .and_modify(|x| x.push(i))
                     .or_insert(vec![i]);
             });
+            limit.count_buckets(&store);
             right_string_vec.par_iter().enumerate().for_each(|(j, x)| {
+                if interrupted() {
+                    return;
+                }
                 let hash = hasher.hash(x);
                 if store.contains_key(&hash) {
                     let potential_matches = store.get(&hash).unwrap();
+                    let mut counts = ProbeCounts {
+                        candidates: potential_matches.len(),
+                        ..ProbeCounts::default()
+                    };
+                    if !limit.admits(potential_matches.len()) {
+                        diagnostics.add(counts);
+                        return;
+                    }
 
                     for i in potential_matches.iter() {
+                        if candidates {
+                            let mut n = collisions.entry((*i, j)).or_insert(0);
+                            if *n == 0 {
+                                counts.accepted += 1;
+                            }
+                            *n += 1;
+                            continue;
+                        }
+                        counts.verified += 1;
                         let dist = left_string_vec[*i]
                             .as_bytes()
                             .iter()
@@ -265,28 +1173,27 @@ fn rust_hamming_join(
                             .filter(|x| *x)
                             .count();
 
-                        if dist <= radius as usize {
-                            pairs.insert((*i, j));
+                        if dist <= radius as usize && pairs.insert((*i, j)) {
+                            counts.accepted += 1;
                         }
                     }
+                    diagnostics.add(counts);
                 }
             });
-        });
+        })?;
 
+        diagnostics.finish_band(started, &store, progress);
         store.clear()
     }
 
-    let mut out_arr: Array2<u64> = Array2::zeros((pairs.len(), 2));
-
-    for (idx, (i, j)) in pairs.into_iter().enumerate() {
-        out_arr[[idx, 0]] = i as u64 + 1;
-        out_arr[[idx, 1]] = j as u64 + 1;
-    }
-
-    Robj::try_from(&out_arr).into()
+    Ok(with_diagnostics(
+        with_dropped_buckets(match_table(pairs, collisions, candidates), &limit),
+        &diagnostics,
+    ))
 }
 
 #[extendr]
+#[allow(clippy::too_many_arguments)]
 fn rust_p_norm_join(
     a_mat: Robj,
     b_mat: Robj,
@@ -294,17 +1201,59 @@ fn rust_p_norm_join(
     band_width: u64,
     n_bands: u64,
     r: f64,
+    n_probes: usize,
+    max_bucket_size: Option<usize>,
+    candidates: bool,
     progress: bool,
     seed: u64,
     nthread: Option<usize>,
 ) -> Robj {
-    let pool = get_pool(nthread);
+    let result = {
+        let pool = get_pool(nthread);
 
-    let a_mat = <ArrayView2<f64>>::try_from(&a_mat).unwrap().to_owned();
-    let b_mat = <ArrayView2<f64>>::try_from(&b_mat).unwrap().to_owned();
+        let a_mat = <ArrayView2<f64>>::try_from(&a_mat).unwrap().to_owned();
+        let b_mat = <ArrayView2<f64>>::try_from(&b_mat).unwrap().to_owned();
+
+        p_norm_join(
+            a_mat.view(),
+            b_mat.view(),
+            radius,
+            band_width,
+            n_bands,
+            r,
+            n_probes,
+            max_bucket_size,
+            candidates,
+            progress,
+            seed,
+            &pool,
+        )
+    };
+    result.unwrap_or_else(|interrupted| interrupted.throw())
+}
 
+/// The rows of `b_mat` within Euclidean distance `radius` of those of
+/// `a_mat`, found band by band, as the output of `rust_p_norm_join`.
+#[allow(clippy::too_many_arguments)]
+fn p_norm_join(
+    a_mat: ArrayView2<f64>,
+    b_mat: ArrayView2<f64>,
+    radius: f64,
+    band_width: u64,
+    n_bands: u64,
+    r: f64,
+    n_probes: usize,
+    max_bucket_size: Option<usize>,
+    candidates: bool,
+    progress: bool,
+    seed: u64,
+    pool: &ThreadPool,
+) -> Result<Robj, Interrupted> {
     let pairs: DashSet<(usize, usize)> = DashSet::new();
+    let collisions: DashMap<(usize, usize), u64> = DashMap::new();
     let store: DashMap<u64, Vec<usize>> = DashMap::new();
+    let limit = BucketLimit::new(max_bucket_size);
+    let diagnostics = JoinDiagnostics::default();
 
     let mut rng = StdRng::seed_from_u64(seed);
     for i in 0..n_bands {
@@ -313,8 +1262,9 @@ fn rust_p_norm_join(
         if progress {
             rprintln!("starting band {i} out of {n_bands}");
         }
+        let started = Instant::now();
 
-        pool.install(|| {
+        interrupt::install(pool, || {
             a_mat
                 .axis_iter(Axis(0))
                 .into_par_iter()
@@ -327,17 +1277,37 @@ fn rust_p_norm_join(
                         .and_modify(|x| x.push(i))
                         .or_insert(vec![i]);
                 });
+            limit.count_buckets(&store);
 
             b_mat
                 .axis_iter(Axis(0))
                 .into_par_iter()
                 .enumerate()
                 .for_each(|(j, x)| {
-                    let hash = hasher.hash(x);
-                    if store.contains_key(&hash) {
-                        let potential_matches = store.get(&hash).unwrap();
+                    if interrupted() {
+                        return;
+                    }
+                    let mut counts = ProbeCounts::default();
+                    for hash in hasher.hash_probes(x, n_probes) {
+                        let potential_matches = match store.get(&hash) {
+                            Some(matches) => matches,
+                            None => continue,
+                        };
+                        counts.candidates += potential_matches.len();
+                        if !limit.admits(potential_matches.len()) {
+                            continue;
+                        }
 
                         for i in potential_matches.iter() {
+                            if candidates {
+                                let mut n = collisions.entry((*i, j)).or_insert(0);
+                                if *n == 0 {
+                                    counts.accepted += 1;
+                                }
+                                *n += 1;
+                                continue;
+                            }
+                            counts.verified += 1;
                             let dist: f64 = b_mat
                                 .row(j)
                                 .iter()
@@ -346,16 +1316,42 @@ fn rust_p_norm_join(
                                 .sum::<f64>()
                                 .sqrt();
 
-                            if dist < radius {
-                                pairs.insert((*i, j));
+                            if dist < radius && pairs.insert((*i, j)) {
+                                counts.accepted += 1;
                             }
                         }
                     }
+                    diagnostics.add(counts);
                 });
-        });
+        })?;
+        diagnostics.finish_band(started, &store, progress);
         store.clear()
     }
 
+    Ok(with_diagnostics(
+        with_dropped_buckets(match_table(pairs, collisions, candidates), &limit),
+        &diagnostics,
+    ))
+}
+
+/// The output of the Hamming and Euclidean joins: the matched `pairs`, or
+/// with `candidates` every pair that collided along with its number of
+/// `collisions`, as 1-based rows.
+fn match_table(
+    pairs: DashSet<(usize, usize)>,
+    collisions: DashMap<(usize, usize), u64>,
+    candidates: bool,
+) -> Robj {
+    if candidates {
+        let mut out_arr: Array2<u64> = Array2::zeros((collisions.len(), 3));
+        for (idx, ((i, j), n_collisions)) in collisions.into_iter().enumerate() {
+            out_arr[[idx, 0]] = i as u64 + 1;
+            out_arr[[idx, 1]] = j as u64 + 1;
+            out_arr[[idx, 2]] = n_collisions;
+        }
+        return Robj::try_from(&out_arr).into();
+    }
+
     let mut out_arr: Array2<u64> = Array2::zeros((pairs.len(), 2));
 
     for (idx, (i, j)) in pairs.into_iter().enumerate() {
@@ -373,11 +1369,17 @@ extendr_module! {
     mod zoomerjoin;
     fn rust_jaccard_join;
     fn rust_salted_jaccard_join;
+    fn rust_jaccard_self_join;
+    fn rust_containment_join;
     fn rust_jaccard_similarity;
     fn rust_em_link;
     fn rust_p_norm_join;
     fn rust_hamming_join;
     fn rust_hamming_distance;
+    fn rust_phonetic_encode;
+    fn rust_jaccard_signatures;
+    fn rust_signature_join;
+    impl JaccardIndex;
 }
 
 fn get_pool(nthread: Option<usize>) -> ThreadPool {
@@ -392,3 +1394,99 @@ fn get_pool(nthread: Option<usize>) -> ThreadPool {
             .expect("Failed to build default thread pool")
     }
 }
+
+fn hash_scheme(hasher: &str, weighted: bool) -> HashScheme {
+    let scheme: HashScheme = hasher.parse().unwrap_or_else(|e: String| throw_r_error(e));
+    if weighted && scheme != HashScheme::MinHash {
+        throw_r_error("weighted joins require hasher = 'minhash'");
+    }
+    scheme
+}
+
+/// Reject the combinations of join modes that `jaccard_join` cannot run.
+#[allow(clippy::too_many_arguments)]
+fn check_join_modes(
+    scheme: HashScheme,
+    weighted: bool,
+    estimate: bool,
+    exact: bool,
+    candidates: bool,
+    top_k: Option<usize>,
+    n_probes: usize,
+    threshold: f64,
+) {
+    if candidates && (top_k.is_some() || estimate || exact) {
+        throw_r_error("candidate joins cannot keep the top k, estimate or be exact");
+    }
+    if exact && (top_k.is_some() || estimate) {
+        throw_r_error("exact joins cannot keep the top k or estimate similarities");
+    }
+    if exact && weighted {
+        throw_r_error("exact joins cannot be weighted");
+    }
+    if exact && threshold <= 0.0 {
+        throw_r_error("exact joins need a threshold greater than 0");
+    }
+    if top_k.is_some() && estimate {
+        throw_r_error("top-k joins cannot estimate similarities");
+    }
+    if n_probes > 0 && (weighted || scheme != HashScheme::MinHash) {
+        throw_r_error("multi-probe joins require hasher = 'minhash' and weighted = FALSE");
+    }
+}
+
+fn check_shingle_bits(bits: i32) {
+    if bits != 32 && bits != 64 {
+        throw_r_error("'shingle_bits' must be 32 or 64");
+    }
+}
+
+fn signature_bits(bits: i32) -> u32 {
+    if !(1..=32).contains(&bits) {
+        throw_r_error("'bits' must be between 1 and 32");
+    }
+    bits as u32
+}
+
+fn shingle_config(
+    ngram_width: Robj,
+    tokenizer: &str,
+    normalize: Robj,
+    phonetic: Robj,
+    pad: bool,
+    multiset: bool,
+) -> ShingleConfig {
+    // Integer widths go through `f64` too, so that `NA_integer_` (which is
+    // `i32::MIN`) is rejected along with negative, `NA` and fractional widths.
+    let widths: Vec<f64> = match (
+        ngram_width.as_real_vector(),
+        ngram_width.as_integer_vector(),
+    ) {
+        (Some(widths), _) => widths,
+        (None, Some(widths)) => widths.into_iter().map(f64::from).collect(),
+        (None, None) => throw_r_error("'ngram_width' must be numeric"),
+    };
+    if widths.is_empty() || !widths.iter().all(|w| *w >= 1.0 && w.fract() == 0.0) {
+        throw_r_error(
+            "'ngram_width' must contain at least one width, all whole numbers greater than 0",
+        );
+    }
+    let widths: Vec<usize> = widths.into_iter().map(|w| w as usize).collect();
+
+    let tokenizer: Tokenizer = tokenizer
+        .parse()
+        .unwrap_or_else(|e: String| throw_r_error(e));
+
+    let steps = normalize.as_str_vector().unwrap_or_default();
+    let normalizer = Normalizer::from_steps(&steps).unwrap_or_else(|e| throw_r_error(e));
+
+    let phonetic: Option<PhoneticEncoder> = phonetic
+        .as_str()
+        .map(|encoder| encoder.parse().unwrap_or_else(|e: String| throw_r_error(e)));
+
+    ShingleConfig::new(widths, tokenizer)
+        .with_normalizer(normalizer)
+        .with_phonetic(phonetic)
+        .with_padding(pad)
+        .with_multiset(multiset)
+}
@@ -1,45 +1,122 @@
-use crate::shingleset::ShingleSet;
+use crate::shingleset::{ShingleConfig, ShingleId, ShingleSet};
 
 use extendr_api::prelude::*;
 use rayon::prelude::*;
 use rayon::ThreadPool;
-use std::sync::Arc;
+use std::borrow::Borrow;
+use std::result::Result;
 
-use dashmap::{DashMap, DashSet};
+use dashmap::DashMap;
+use nohash_hasher::{IntMap, IntSet};
 
-use crate::minihasher::MinHasher;
+use crate::buckets::BucketLimit;
+use crate::diagnostics::JoinDiagnostics;
+use crate::ensemble::{ensemble_join, LshEnsemble};
+use crate::interrupt::{self, interrupted, Interrupted};
+use crate::minihasher::{HashScheme, SignatureHasher};
+use crate::ppjoin::ppjoin;
+use crate::signatures::{
+    band_candidates, band_join, band_self_join, band_top_k, MultiProbe, Signatures,
+};
 
 use rand::rngs::StdRng;
 use rand::SeedableRng;
 
-pub struct MinHashJoiner {
-    smaller_set: Vec<ShingleSet>,
-    larger_set: Vec<ShingleSet>,
+/// The strings of one side of a join, with their salts in a blocked join,
+/// for joins that shingle each record when they need it instead of holding
+/// every shingle set at once.
+pub struct Records<'a> {
+    strings: Vec<&'a str>,
+    salts: Option<Vec<&'a str>>,
 }
 
-impl MinHashJoiner {
+impl<'a> Records<'a> {
+    pub fn new(strings: Vec<&'a str>, salts: Option<Vec<&'a str>>) -> Self {
+        Self { strings, salts }
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+
+    /// The shingle set of record `i`, as the joiner's constructors build it.
+    #[inline]
+    fn shingle<T: ShingleId>(&self, i: usize, config: &ShingleConfig) -> ShingleSet<T> {
+        let salt = self.salts.as_ref().map(|salts| salts[i]);
+        ShingleSet::new(self.strings[i], config, i, salt)
+    }
+}
+
+pub struct MinHashJoiner<T: ShingleId = u32> {
+    smaller_set: Vec<ShingleSet<T>>,
+    /// Empty for a joiner built with `new_self`.
+    larger_set: Vec<ShingleSet<T>>,
+    weights: Option<IntMap<T, f64>>,
+    n_probes: usize,
+    limit: BucketLimit,
+    diagnostics: JoinDiagnostics,
+}
+
+impl<T: ShingleId> MinHashJoiner<T> {
+    /// Shingle both sides. This and the other constructors return
+    /// `Interrupted` if the user interrupts, dropping what was shingled.
     pub fn new(
         left_string_vec: Vec<&str>,
         right_string_vec: Vec<&str>,
-        ngram_width: usize,
+        config: &ShingleConfig,
         pool: &ThreadPool,
-    ) -> Self {
-        pool.install(|| {
-            let left_set_vec: Vec<ShingleSet> = left_string_vec
+    ) -> Result<Self, Interrupted> {
+        interrupt::install(pool, || {
+            let left_set_vec: Vec<ShingleSet<T>> = left_string_vec
                 .par_iter()
                 .enumerate()
-                .map(|(i, x)| ShingleSet::new(x, ngram_width, i, None))
+                .filter(|_| !interrupted())
+                .map(|(i, x)| ShingleSet::new(x, config, i, None))
                 .collect();
 
-            let right_set_vec: Vec<ShingleSet> = right_string_vec
+            let right_set_vec: Vec<ShingleSet<T>> = right_string_vec
                 .par_iter()
                 .enumerate()
-                .map(|(i, x)| ShingleSet::new(x, ngram_width, i, None))
+                .filter(|_| !interrupted())
+                .map(|(i, x)| ShingleSet::new(x, config, i, None))
                 .collect();
 
             Self {
                 smaller_set: left_set_vec,
                 larger_set: right_set_vec,
+                weights: None,
+                n_probes: 0,
+                limit: BucketLimit::default(),
+                diagnostics: JoinDiagnostics::default(),
+            }
+        })
+    }
+
+    /// A joiner for `self_join`, which shingles `string_vec` once.
+    pub fn new_self(
+        string_vec: Vec<&str>,
+        config: &ShingleConfig,
+        pool: &ThreadPool,
+    ) -> Result<Self, Interrupted> {
+        interrupt::install(pool, || {
+            let set_vec: Vec<ShingleSet<T>> = string_vec
+                .par_iter()
+                .enumerate()
+                .filter(|_| !interrupted())
+                .map(|(i, x)| ShingleSet::new(x, config, i, None))
+                .collect();
+
+            Self {
+                smaller_set: set_vec,
+                larger_set: Vec::new(),
+                weights: None,
+                n_probes: 0,
+                limit: BucketLimit::default(),
+                diagnostics: JoinDiagnostics::default(),
             }
         })
     }
@@ -49,81 +126,540 @@ impl MinHashJoiner {
         right_string_vec: Vec<&str>,
         left_salt_vec: Vec<&str>,
         right_salt_vec: Vec<&str>,
-        ngram_width: usize,
+        config: &ShingleConfig,
         pool: &ThreadPool,
-    ) -> Self {
-        pool.install(|| {
-            let left_set_vec: Vec<ShingleSet> = left_string_vec
+    ) -> Result<Self, Interrupted> {
+        interrupt::install(pool, || {
+            let left_set_vec: Vec<ShingleSet<T>> = left_string_vec
                 .par_iter()
                 .zip(left_salt_vec)
                 .enumerate()
-                .map(|(i, (string, salt))| ShingleSet::new(string, ngram_width, i, Some(salt)))
+                .filter(|_| !interrupted())
+                .map(|(i, (string, salt))| ShingleSet::new(string, config, i, Some(salt)))
                 .collect();
 
-            let right_set_vec: Vec<ShingleSet> = right_string_vec
+            let right_set_vec: Vec<ShingleSet<T>> = right_string_vec
                 .par_iter()
                 .zip(right_salt_vec)
                 .enumerate()
-                .map(|(i, (string, salt))| ShingleSet::new(string, ngram_width, i, Some(salt)))
+                .filter(|_| !interrupted())
+                .map(|(i, (string, salt))| ShingleSet::new(string, config, i, Some(salt)))
                 .collect();
 
             Self {
                 smaller_set: left_set_vec,
                 larger_set: right_set_vec,
+                weights: None,
+                n_probes: 0,
+                limit: BucketLimit::default(),
+                diagnostics: JoinDiagnostics::default(),
             }
         })
     }
 
+    /// A joiner that holds no shingle sets, for `estimate_join`, which
+    /// shingles each record only to sign it.
+    pub fn unshingled() -> Self {
+        Self {
+            smaller_set: Vec::new(),
+            larger_set: Vec::new(),
+            weights: None,
+            n_probes: 0,
+            limit: BucketLimit::default(),
+            diagnostics: JoinDiagnostics::default(),
+        }
+    }
+
+    /// Weight each shingle by its inverse document frequency across both
+    /// inputs, so that `join` hashes with weighted MinHash and verifies
+    /// candidates with the weighted Jaccard similarity. Shingle sets hold each
+    /// shingle once, so the term frequency is always one and a shingle's
+    /// TF-IDF weight is its IDF.
+    pub fn with_idf_weights(mut self, pool: &ThreadPool) -> Self {
+        let n_docs = self.smaller_set.len() + self.larger_set.len();
+        self.weights = Some(idf_weights(self.document_frequencies(pool), n_docs));
+        self
+    }
+
+    /// Like `with_idf_weights`, for a joiner built with `unshingled`: the
+    /// document frequencies are counted from `smaller` and `larger`, shingled
+    /// one record at a time, so their sets are never all held at once.
+    pub fn with_record_idf_weights(
+        mut self,
+        smaller: &Records,
+        larger: &Records,
+        config: &ShingleConfig,
+        pool: &ThreadPool,
+    ) -> Result<Self, Interrupted> {
+        let n_docs = smaller.len() + larger.len();
+        let frequencies = interrupt::install(pool, || {
+            let sets = (0..smaller.len())
+                .into_par_iter()
+                .map(|i| (smaller, i))
+                .chain((0..larger.len()).into_par_iter().map(|i| (larger, i)))
+                .filter(|_| !interrupted())
+                .map(|(records, i)| records.shingle::<T>(i, config));
+            count_documents(sets)
+        })?;
+        self.weights = Some(idf_weights(frequencies, n_docs));
+        Ok(self)
+    }
+
+    /// The number of distinct shingle ids across both inputs, and how many
+    /// distinct ids remain once they are truncated to 32 bits.
+    pub fn distinct_shingles(&self, pool: &ThreadPool) -> (usize, usize) {
+        pool.install(|| {
+            let ids: IntSet<T> = self
+                .smaller_set
+                .par_iter()
+                .chain(self.larger_set.par_iter())
+                .fold(IntSet::default, |mut acc: IntSet<T>, shingleset| {
+                    acc.extend(&shingleset.shingles);
+                    acc
+                })
+                .reduce(IntSet::default, |mut a, b| {
+                    a.extend(b);
+                    a
+                });
+
+            let truncated: IntSet<u32> = ids.iter().map(|id| u32::from_hash(id.as_u64())).collect();
+
+            (ids.len(), truncated.len())
+        })
+    }
+
+    /// The number of records across both inputs that have each shingle.
+    fn document_frequencies(&self, pool: &ThreadPool) -> IntMap<T, u32> {
+        pool.install(|| {
+            count_documents(
+                self.smaller_set
+                    .par_iter()
+                    .chain(self.larger_set.par_iter()),
+            )
+        })
+    }
+
+    /// Probe `n_probes` variants of every band of the larger side as well as
+    /// its own bucket (see `MultiProbe`). Needs unweighted classic MinHash.
+    pub fn with_multi_probe(mut self, n_probes: usize) -> Self {
+        self.n_probes = n_probes;
+        self
+    }
+
+    /// Skip buckets of more than `max_size` records in `join`, `top_k_join`
+    /// and `estimate_join` (see `BucketLimit`).
+    pub fn with_max_bucket_size(mut self, max_size: Option<usize>) -> Self {
+        self.limit = BucketLimit::new(max_size);
+        self
+    }
+
+    /// The buckets and candidate pairs skipped by the joins run so far.
+    pub fn bucket_limit(&self) -> &BucketLimit {
+        &self.limit
+    }
+
+    /// The per-band work of the banded joins run so far.
+    pub fn diagnostics(&self) -> &JoinDiagnostics {
+        &self.diagnostics
+    }
+
+    /// Signature of one record, weighted if the joiner has IDF weights.
+    #[inline]
+    fn sign(&self, hasher: &SignatureHasher, shingleset: &ShingleSet<T>, out: &mut [u32]) {
+        match (hasher, &self.weights) {
+            (SignatureHasher::MinHash(hasher), Some(weights)) => {
+                hasher.weighted_signature(shingleset, weights, out)
+            }
+            (hasher, _) => hasher.signature(shingleset, out),
+        }
+    }
+
+    #[inline]
+    fn similarity(&self, a: &ShingleSet<T>, b: &ShingleSet<T>) -> f64 {
+        match &self.weights {
+            Some(weights) => a.weighted_jaccard_similarity(b, weights),
+            None => a.jaccard_similarity(b),
+        }
+    }
+
+    /// Signatures of both sides, keeping `bits` bits per value, in the order
+    /// of `smaller_set` and `larger_set`.
+    pub fn signatures(
+        &self,
+        hasher: &SignatureHasher,
+        len: usize,
+        bits: u32,
+        pool: &ThreadPool,
+    ) -> Result<(Signatures, Signatures), Interrupted> {
+        let smaller = Signatures::compute(self.smaller_set.len(), len, bits, pool, |i, out| {
+            self.sign(hasher, &self.smaller_set[i], out)
+        })?;
+        let larger = Signatures::compute(self.larger_set.len(), len, bits, pool, |i, out| {
+            self.sign(hasher, &self.larger_set[i], out)
+        })?;
+        Ok((smaller, larger))
+    }
+
+    /// Runner-up signatures of the larger side, if the joiner probes
+    /// variants of its bands.
+    fn runner_up_signatures(
+        &self,
+        hasher: &SignatureHasher,
+        len: usize,
+        pool: &ThreadPool,
+    ) -> Result<Option<Signatures>, Interrupted> {
+        match hasher {
+            SignatureHasher::MinHash(hasher) if self.n_probes > 0 => {
+                Signatures::compute(self.larger_set.len(), len, 32, pool, |i, out| {
+                    hasher.runner_up_signature(&self.larger_set[i], out)
+                })
+                .map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Draw the signature hasher for a join, as `join` and `estimate_join`
+    /// both do. Weighted and multi-probe joins need plain MinHash, which the
+    /// entry points check before shingling.
+    fn hasher(&self, scheme: HashScheme, len: usize, seed: u64) -> SignatureHasher {
+        debug_assert!(self.weights.is_none() || scheme == HashScheme::MinHash);
+        debug_assert!(
+            self.n_probes == 0 || (self.weights.is_none() && scheme == HashScheme::MinHash)
+        );
+        let mut rng = StdRng::seed_from_u64(seed);
+        SignatureHasher::new(scheme, len, &mut rng)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn join(
         &self,
         n_bands: usize,
         band_size: usize,
         threshold: f64,
+        scheme: HashScheme,
         progress: bool,
         seed: u64,
         pool: &ThreadPool,
-    ) -> DashSet<(usize, usize)> {
-        //let mut matched_pairs: HashSet<(usize, usize)> = HashSet::new();
-        let matched_pairs: DashSet<(usize, usize)> = DashSet::new();
+    ) -> Result<DashMap<(usize, usize), f64>, Interrupted> {
+        // Every record is hashed once, for all bands at a time; each band
+        // then only hashes its slice of the signatures into a bucket key.
+        let hasher = self.hasher(scheme, n_bands * band_size, seed);
+        if progress {
+            rprintln!("Computing signatures");
+        }
+        let (smaller_sigs, larger_sigs) =
+            self.signatures(&hasher, n_bands * band_size, 32, pool)?;
+        let runner_up = self.runner_up_signatures(&hasher, n_bands * band_size, pool)?;
+        let probe = runner_up.as_ref().map(|runner_up| MultiProbe {
+            runner_up,
+            n_probes: self.n_probes,
+        });
 
-        let mut rng = StdRng::seed_from_u64(seed);
-        let small_set_map: Arc<DashMap<u64, Vec<usize>>> =
-            Arc::new(DashMap::with_capacity(self.smaller_set.len()));
-        for i in 0..n_bands {
-            if progress {
-                rprintln!("starting band {i} out of {n_bands}");
-            }
+        band_join(
+            &smaller_sigs,
+            &larger_sigs,
+            probe.as_ref(),
+            &self.limit,
+            &self.diagnostics,
+            n_bands,
+            band_size,
+            threshold,
+            progress,
+            pool,
+            |larger, smaller| self.similarity(&self.larger_set[larger], &self.smaller_set[smaller]),
+        )
+    }
 
-            let hasher = MinHasher::new(band_size as usize, &mut rng);
+    /// The candidate pairs `join` would verify, as `(larger, smaller)` pairs
+    /// with the number of bands each collided in, without verifying them.
+    pub fn candidate_join(
+        &self,
+        n_bands: usize,
+        band_size: usize,
+        scheme: HashScheme,
+        progress: bool,
+        seed: u64,
+        pool: &ThreadPool,
+    ) -> Result<DashMap<(usize, usize), u32>, Interrupted> {
+        let hasher = self.hasher(scheme, n_bands * band_size, seed);
+        if progress {
+            rprintln!("Computing signatures");
+        }
+        let (smaller_sigs, larger_sigs) =
+            self.signatures(&hasher, n_bands * band_size, 32, pool)?;
+        let runner_up = self.runner_up_signatures(&hasher, n_bands * band_size, pool)?;
+        let probe = runner_up.as_ref().map(|runner_up| MultiProbe {
+            runner_up,
+            n_probes: self.n_probes,
+        });
 
-            pool.install(|| {
-                self.smaller_set.par_iter().for_each(|shingleset| {
-                    let key = hasher.hash(&shingleset);
+        band_candidates(
+            &smaller_sigs,
+            &larger_sigs,
+            probe.as_ref(),
+            &self.limit,
+            &self.diagnostics,
+            n_bands,
+            band_size,
+            progress,
+            pool,
+        )
+    }
 
-                    small_set_map
-                        .entry(key)
-                        .and_modify(|x| x.push(shingleset.index))
-                        .or_insert(vec![shingleset.index]);
-                });
-                self.larger_set.par_iter().for_each(|shingleset| {
-                    let key = hasher.hash(&shingleset);
-                    if small_set_map.contains_key(&key) {
-                        for matched in small_set_map.get(&key).unwrap().iter() {
-                            if !matched_pairs.contains(&(shingleset.index, *matched)) {
-                                if shingleset.jaccard_similarity(&self.smaller_set[*matched])
-                                    >= threshold
-                                {
-                                    matched_pairs.insert((shingleset.index, *matched));
-                                }
-                            }
-                        }
-                    }
-                });
-            });
+    /// Join the strings of a joiner built with `new_self` against
+    /// themselves. Every string is signed once, and only pairs `(i, j)` with
+    /// `i < j` are compared and returned, along with their similarity.
+    #[allow(clippy::too_many_arguments)]
+    pub fn self_join(
+        &self,
+        n_bands: usize,
+        band_size: usize,
+        threshold: f64,
+        scheme: HashScheme,
+        progress: bool,
+        seed: u64,
+        pool: &ThreadPool,
+    ) -> Result<DashMap<(usize, usize), f64>, Interrupted> {
+        let hasher = self.hasher(scheme, n_bands * band_size, seed);
+        if progress {
+            rprintln!("Computing signatures");
+        }
+        let (sigs, _) = self.signatures(&hasher, n_bands * band_size, 32, pool)?;
+
+        band_self_join(
+            &sigs,
+            &self.limit,
+            &self.diagnostics,
+            n_bands,
+            band_size,
+            threshold,
+            progress,
+            pool,
+            |i, j| self.similarity(&self.smaller_set[i], &self.smaller_set[j]),
+        )
+    }
+
+    /// Like `join`, but keep only the `k` most similar right records for
+    /// each left record, among those whose similarity reaches `threshold`.
+    /// Returns `(left, right, similarity)` triples, grouped by left record
+    /// and from most to least similar.
+    #[allow(clippy::too_many_arguments)]
+    pub fn top_k_join(
+        &self,
+        k: usize,
+        n_bands: usize,
+        band_size: usize,
+        threshold: f64,
+        scheme: HashScheme,
+        progress: bool,
+        seed: u64,
+        pool: &ThreadPool,
+    ) -> Result<Vec<(usize, usize, f64)>, Interrupted> {
+        let hasher = self.hasher(scheme, n_bands * band_size, seed);
+        if progress {
+            rprintln!("Computing signatures");
+        }
+        let (smaller_sigs, larger_sigs) =
+            self.signatures(&hasher, n_bands * band_size, 32, pool)?;
+        let runner_up = self.runner_up_signatures(&hasher, n_bands * band_size, pool)?;
+        let probe = runner_up.as_ref().map(|runner_up| MultiProbe {
+            runner_up,
+            n_probes: self.n_probes,
+        });
+
+        band_top_k(
+            &smaller_sigs,
+            &larger_sigs,
+            probe.as_ref(),
+            &self.limit,
+            &self.diagnostics,
+            n_bands,
+            band_size,
+            k,
+            threshold,
+            progress,
+            pool,
+            |larger, smaller| self.similarity(&self.larger_set[larger], &self.smaller_set[smaller]),
+        )
+    }
+
+    /// Every `(left, right, similarity)` triple whose Jaccard similarity
+    /// reaches `threshold`, found by prefix filtering (see `ppjoin`) rather
+    /// than hashing, so that no match is missed. The joiner must be
+    /// unweighted and `threshold` greater than 0, which the entry points
+    /// check before shingling.
+    pub fn exact_join(
+        &self,
+        threshold: f64,
+        progress: bool,
+        pool: &ThreadPool,
+    ) -> Result<Vec<(usize, usize, f64)>, Interrupted> {
+        debug_assert!(self.weights.is_none() && threshold > 0.0);
+        let doc_freqs = self.document_frequencies(pool);
+        ppjoin(
+            &self.smaller_set,
+            &self.larger_set,
+            &doc_freqs,
+            threshold,
+            progress,
+            pool,
+        )
+    }
+
+    /// Find the larger-side records that contain each smaller-side record:
+    /// the `(smaller, larger, containment)` triples whose containment of the
+    /// smaller record's shingles in the larger record's reaches `threshold`.
+    /// Candidates come from an `LshEnsemble` over the larger side with
+    /// `n_partitions` size partitions, and are verified exactly.
+    #[allow(clippy::too_many_arguments)]
+    pub fn containment_join(
+        &self,
+        n_bands: usize,
+        band_size: usize,
+        n_partitions: usize,
+        threshold: f64,
+        progress: bool,
+        seed: u64,
+        pool: &ThreadPool,
+    ) -> Result<Vec<(usize, usize, f64)>, Interrupted> {
+        let hasher = self.hasher(HashScheme::MinHash, n_bands * band_size, seed);
+        if progress {
+            rprintln!("Computing signatures");
+        }
+        let (smaller_sigs, larger_sigs) =
+            self.signatures(&hasher, n_bands * band_size, 32, pool)?;
 
-            small_set_map.clear()
+        let larger_sizes: Vec<usize> = self.larger_set.iter().map(|x| x.shingles.len()).collect();
+        let smaller_sizes: Vec<usize> = self.smaller_set.iter().map(|x| x.shingles.len()).collect();
+
+        if progress {
+            rprintln!("Partitioning {} records by size", larger_sizes.len());
         }
+        let ensemble = LshEnsemble::new(
+            &larger_sigs,
+            &larger_sizes,
+            n_bands,
+            band_size,
+            n_partitions,
+            pool,
+        );
 
-        matched_pairs
+        ensemble_join(
+            &ensemble,
+            &smaller_sigs,
+            &smaller_sizes,
+            threshold,
+            &self.diagnostics,
+            progress,
+            pool,
+            |smaller, larger| self.smaller_set[smaller].containment(&self.larger_set[larger]),
+        )
     }
+
+    /// Like `join`, but candidates are verified by the fraction of signature
+    /// values on which they agree rather than by their exact similarity, so
+    /// the join never needs the shingle sets. Called on a joiner built with
+    /// `unshingled`, it shingles each record of `smaller` and `larger` only
+    /// to sign it and drops the set straight away, so that at most one set
+    /// per thread is held next to the signatures. Returns `(larger, smaller,
+    /// estimate, standard error)` for every matched pair.
+    #[allow(clippy::too_many_arguments)]
+    pub fn estimate_join(
+        &self,
+        smaller: &Records,
+        larger: &Records,
+        config: &ShingleConfig,
+        n_bands: usize,
+        band_size: usize,
+        threshold: f64,
+        scheme: HashScheme,
+        progress: bool,
+        seed: u64,
+        pool: &ThreadPool,
+    ) -> Result<Vec<(usize, usize, f64, f64)>, Interrupted> {
+        let len = n_bands * band_size;
+        let hasher = self.hasher(scheme, len, seed);
+        if progress {
+            rprintln!("Shingling and signing records");
+        }
+        let smaller_sigs = Signatures::compute(smaller.len(), len, 32, pool, |i, out| {
+            self.sign(&hasher, &smaller.shingle(i, config), out)
+        })?;
+        let larger_sigs = Signatures::compute(larger.len(), len, 32, pool, |i, out| {
+            self.sign(&hasher, &larger.shingle(i, config), out)
+        })?;
+        // Multi-probe joins shingle the larger side a second time for its
+        // runner-up signatures, rather than keep its sets between the passes.
+        let runner_up = match &hasher {
+            SignatureHasher::MinHash(hasher) if self.n_probes > 0 => Some(Signatures::compute(
+                larger.len(),
+                len,
+                32,
+                pool,
+                |i, out| hasher.runner_up_signature(&larger.shingle::<T>(i, config), out),
+            )?),
+            _ => None,
+        };
+        let probe = runner_up.as_ref().map(|runner_up| MultiProbe {
+            runner_up,
+            n_probes: self.n_probes,
+        });
+
+        let pairs = band_join(
+            &smaller_sigs,
+            &larger_sigs,
+            probe.as_ref(),
+            &self.limit,
+            &self.diagnostics,
+            n_bands,
+            band_size,
+            threshold,
+            progress,
+            pool,
+            |larger, smaller| larger_sigs.estimate(larger, &smaller_sigs, smaller),
+        )?;
+
+        Ok(pairs
+            .into_iter()
+            .map(|((larger, smaller), estimate)| {
+                (
+                    larger,
+                    smaller,
+                    estimate,
+                    larger_sigs.standard_error(estimate),
+                )
+            })
+            .collect())
+    }
+}
+
+/// The number of `sets` that have each shingle.
+fn count_documents<T, I>(sets: I) -> IntMap<T, u32>
+where
+    T: ShingleId,
+    I: ParallelIterator,
+    I::Item: Borrow<ShingleSet<T>>,
+{
+    sets.fold(IntMap::default, |mut counts: IntMap<T, u32>, shingleset| {
+        for shingle in &shingleset.borrow().shingles {
+            *counts.entry(*shingle).or_insert(0) += 1;
+        }
+        counts
+    })
+    .reduce(IntMap::default, |mut a, b| {
+        for (shingle, count) in b {
+            *a.entry(shingle).or_insert(0) += count;
+        }
+        a
+    })
+}
+
+/// The IDF weight of each shingle, given how many of `n_docs` records have it.
+fn idf_weights<T: ShingleId>(frequencies: IntMap<T, u32>, n_docs: usize) -> IntMap<T, f64> {
+    let n_docs = n_docs as f64;
+    frequencies
+        .into_iter()
+        .map(|(shingle, df)| (shingle, (1.0 + n_docs / df as f64).ln()))
+        .collect()
 }
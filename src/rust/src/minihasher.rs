@@ -1,11 +1,47 @@
+use nohash_hasher::IntMap;
 use rustc_hash::FxHasher;
 use std::hash::{Hash, Hasher};
+use std::str::FromStr;
 
 use ndarray_rand::rand_distr::Uniform;
 
 use rand::Rng;
 
-use crate::shingleset::ShingleSet;
+use crate::shingleset::{ShingleId, ShingleSet};
+
+/// How MinHash signatures are computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashScheme {
+    /// One hash function per signature value (`MinHasher`).
+    MinHash,
+    /// One hash function for the whole signature, with densification
+    /// (`OnePermutationHasher`).
+    OnePermutation,
+}
+
+impl FromStr for HashScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "minhash" => Ok(HashScheme::MinHash),
+            "one_permutation" => Ok(HashScheme::OnePermutation),
+            other => Err(format!(
+                "unknown hasher '{other}', expected one of 'minhash' or 'one_permutation'"
+            )),
+        }
+    }
+}
+
+impl HashScheme {
+    /// The name the scheme is parsed from.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashScheme::MinHash => "minhash",
+            HashScheme::OnePermutation => "one_permutation",
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct MinHasher {
@@ -19,8 +55,21 @@ impl MinHasher {
         Self { seeds }
     }
 
-    pub fn hash(&self, shingle_set: &ShingleSet) -> u64 {
-        let mini_hashes = self.seeds.iter().map(|seed| {
+    /// A hasher with the given seeds, one per signature value, such as those
+    /// of a saved index.
+    pub fn from_seeds(seeds: Vec<u64>) -> Self {
+        Self { seeds }
+    }
+
+    pub fn seeds(&self) -> &[u64] {
+        &self.seeds
+    }
+
+    /// Write the MinHash signature of `shingle_set` into `out`, one value
+    /// per seed. Values are the low 32 bits of the minimum hash, and
+    /// `u32::MAX` for an empty set.
+    pub fn signature<T: ShingleId>(&self, shingle_set: &ShingleSet<T>, out: &mut [u32]) {
+        for (seed, out) in self.seeds.iter().zip(out.iter_mut()) {
             let mut min_hash_seen = u64::MAX;
             for item in &shingle_set.shingles {
                 let mut hasher = FxHasher::default();
@@ -34,13 +83,350 @@ impl MinHasher {
                     min_hash_seen = result;
                 }
             }
-            min_hash_seen
-        });
+            *out = min_hash_seen as u32;
+        }
+    }
+
+    /// Like `signature`, but write the second-smallest hash for each seed:
+    /// the value the signature would take there if the set lost its minimum
+    /// shingle. Sets with fewer than two shingles get `u32::MAX`.
+    pub fn runner_up_signature<T: ShingleId>(&self, shingle_set: &ShingleSet<T>, out: &mut [u32]) {
+        for (seed, out) in self.seeds.iter().zip(out.iter_mut()) {
+            let mut min_hash_seen = u64::MAX;
+            let mut runner_up = u64::MAX;
+            for item in &shingle_set.shingles {
+                let mut hasher = FxHasher::default();
+
+                seed.hash(&mut hasher);
+                item.hash(&mut hasher);
+
+                let result: u64 = hasher.finish();
+
+                if result < min_hash_seen {
+                    runner_up = min_hash_seen;
+                    min_hash_seen = result;
+                } else if result < runner_up {
+                    runner_up = result;
+                }
+            }
+            *out = runner_up as u32;
+        }
+    }
+
+    /// Weighted analogue of `signature` using Ioffe's Improved Consistent
+    /// Weighted Sampling (ICWS). Each seed samples one `(shingle, t)` pair,
+    /// and two sets agree on a sample with probability equal to their
+    /// weighted Jaccard similarity. Shingles missing from `weights` or with a
+    /// non-positive weight are ignored.
+    pub fn weighted_signature<T: ShingleId>(
+        &self,
+        shingle_set: &ShingleSet<T>,
+        weights: &IntMap<T, f64>,
+        out: &mut [u32],
+    ) {
+        for (seed, out) in self.seeds.iter().zip(out.iter_mut()) {
+            let mut best_a = f64::INFINITY;
+            let mut best_sample = (u64::MAX, i64::MAX);
+            for item in &shingle_set.shingles {
+                let weight = match weights.get(item) {
+                    Some(w) if *w > 0.0 => *w,
+                    _ => continue,
+                };
+
+                let item = item.as_u64();
+                let r = gamma_2_1(*seed, item, 0);
+                let c = gamma_2_1(*seed, item, 2);
+                let beta = unit_uniform(*seed, item, 4);
+
+                let t = (weight.ln() / r + beta).floor();
+                let y = (r * (t - beta)).exp();
+                let a = c / (y * r.exp());
+
+                if a < best_a {
+                    best_a = a;
+                    best_sample = (item, t as i64);
+                }
+            }
+            let mut hasher = FxHasher::default();
+            best_sample.hash(&mut hasher);
+            *out = hasher.finish() as u32;
+        }
+    }
+}
+
+/// One Permutation Hashing (Li, Owen & Zhang, 2012) with the fast
+/// densification of Mai et al. (2020). Each shingle is hashed once and falls
+/// into one of `n_bins` bins, keeping the minimum hash per bin, so a whole
+/// signature costs one pass over the shingles rather than one pass per
+/// signature value. Bins that no shingle fell into borrow the value of a
+/// non-empty bin, chosen consistently across records, so two signatures
+/// still agree in each position with probability equal to the Jaccard
+/// similarity of their sets.
+#[derive(Debug)]
+pub struct OnePermutationHasher {
+    n_bins: usize,
+    seed: u64,
+}
+
+impl OnePermutationHasher {
+    pub fn new<R: Rng>(n_bins: usize, rand_state: &mut R) -> Self {
+        Self {
+            n_bins,
+            seed: rand_state.gen(),
+        }
+    }
+
+    /// A hasher of `n_bins` bins keyed on `seed`, such as that of a saved
+    /// index.
+    pub fn from_seed(n_bins: usize, seed: u64) -> Self {
+        Self { n_bins, seed }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Write the signature of `shingle_set` into `out`, which must hold
+    /// `n_bins` values. Values are the low 32 bits of the minimum hash in
+    /// each bin, and `u32::MAX` for an empty set.
+    pub fn signature<T: ShingleId>(&self, shingle_set: &ShingleSet<T>, out: &mut [u32]) {
+        let mut bins = vec![u64::MAX; self.n_bins];
+        let mut filled = vec![false; self.n_bins];
+        for item in &shingle_set.shingles {
+            let hash = splitmix64(self.seed ^ item.as_u64());
+            let bin = self.bin(hash);
+            bins[bin] = bins[bin].min(hash);
+            filled[bin] = true;
+        }
+
+        // Fast densification: in round t, every originally non-empty bin j
+        // offers its value to bin h(j, t), which takes it if still empty. An
+        // empty bin ends up with the value of the first (t, j) to hit it,
+        // which depends only on which bins are non-empty.
+        let sources: Vec<usize> = (0..self.n_bins).filter(|&j| filled[j]).collect();
+        let mut n_empty = self.n_bins - sources.len();
+        let mut round: u64 = 0;
+        while n_empty > 0 && !sources.is_empty() {
+            round += 1;
+            for &source in &sources {
+                let target = self.bin(splitmix64(
+                    splitmix64(self.seed ^ source as u64).wrapping_add(round),
+                ));
+                if !filled[target] {
+                    bins[target] = bins[source];
+                    filled[target] = true;
+                    n_empty -= 1;
+                }
+            }
+        }
+
+        for (out, bin) in out.iter_mut().zip(bins) {
+            *out = bin as u32;
+        }
+    }
+
+    /// Map a hash to a bin using its high bits.
+    #[inline]
+    fn bin(&self, hash: u64) -> usize {
+        ((hash as u128 * self.n_bins as u128) >> 64) as usize
+    }
+}
+
+/// A hasher that produces whole signatures under either scheme.
+#[derive(Debug)]
+pub enum SignatureHasher {
+    MinHash(MinHasher),
+    OnePermutation(OnePermutationHasher),
+}
+
+impl SignatureHasher {
+    /// Draw a hasher for signatures of `len` values. Classic MinHash draws
+    /// its seeds in the same order as one `MinHasher` per band would, so
+    /// signatures do not depend on how they are split into bands.
+    pub fn new<R: Rng>(scheme: HashScheme, len: usize, rand_state: &mut R) -> Self {
+        match scheme {
+            HashScheme::MinHash => SignatureHasher::MinHash(MinHasher::new(len, rand_state)),
+            HashScheme::OnePermutation => {
+                SignatureHasher::OnePermutation(OnePermutationHasher::new(len, rand_state))
+            }
+        }
+    }
 
-        let mut hasher = FxHasher::default();
-        for mini_hash in mini_hashes {
-            mini_hash.hash(&mut hasher);
+    pub fn signature<T: ShingleId>(&self, shingle_set: &ShingleSet<T>, out: &mut [u32]) {
+        match self {
+            SignatureHasher::MinHash(hasher) => hasher.signature(shingle_set, out),
+            SignatureHasher::OnePermutation(hasher) => hasher.signature(shingle_set, out),
         }
-        hasher.finish()
+    }
+}
+
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/// Deterministic draw from Uniform(0, 1] keyed on a seed, a shingle, and a
+/// stream number, so that every record sees the same randomness for the same
+/// shingle.
+fn unit_uniform(seed: u64, item: u64, stream: u64) -> f64 {
+    let mut x = seed
+        ^ item.wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ stream.wrapping_mul(0xD1B5_4A32_D192_ED03);
+    // splitmix64 finaliser
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^= x >> 31;
+    ((x >> 11) as f64 + 1.0) / (1u64 << 53) as f64
+}
+
+/// Deterministic draw from Gamma(2, 1), as the sum of two exponentials.
+/// Consumes streams `stream` and `stream + 1`.
+fn gamma_2_1(seed: u64, item: u64, stream: u64) -> f64 {
+    -(unit_uniform(seed, item, stream).ln() + unit_uniform(seed, item, stream + 1).ln())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nohash_hasher::IntSet;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn set(shingles: impl IntoIterator<Item = u32>) -> ShingleSet<u32> {
+        ShingleSet {
+            shingles: shingles.into_iter().collect::<IntSet<u32>>(),
+            index: 0,
+        }
+    }
+
+    fn agreement(a: &[u32], b: &[u32]) -> f64 {
+        a.iter().zip(b).filter(|(a, b)| a == b).count() as f64 / a.len() as f64
+    }
+
+    #[test]
+    fn empty_sets_get_the_maximum_value() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut out = vec![0; 16];
+        OnePermutationHasher::new(16, &mut rng).signature(&set([]), &mut out);
+        assert!(out.iter().all(|value| *value == u32::MAX));
+
+        let mut out = vec![0; 16];
+        MinHasher::new(16, &mut rng).signature(&set([]), &mut out);
+        assert!(out.iter().all(|value| *value == u32::MAX));
+    }
+
+    #[test]
+    fn densification_fills_every_bin_from_a_non_empty_one() {
+        let hasher = OnePermutationHasher::from_seed(64, 7);
+        let shingles = [3, 14, 15];
+        let mut out = vec![0; 64];
+        hasher.signature(&set(shingles), &mut out);
+
+        let hashes: Vec<u32> = shingles
+            .iter()
+            .map(|shingle| splitmix64(hasher.seed ^ *shingle as u64) as u32)
+            .collect();
+        assert!(out.iter().all(|value| hashes.contains(value)));
+
+        // A single shingle fills every bin with its own hash.
+        hasher.signature(&set([42]), &mut out);
+        assert!(out.iter().all(|value| *value == out[0]));
+    }
+
+    #[test]
+    fn densification_is_consistent_across_records() {
+        // Sets much smaller than the signature leave most bins empty, so
+        // the agreement only tracks the Jaccard similarity if the empty
+        // bins of both records borrow from the same places.
+        let hasher = OnePermutationHasher::from_seed(1024, 11);
+        let a = set(0..100);
+        let b = set(50..150);
+        let mut sig_a = vec![0; 1024];
+        let mut sig_b = vec![0; 1024];
+        hasher.signature(&a, &mut sig_a);
+        hasher.signature(&b, &mut sig_b);
+        assert!((agreement(&sig_a, &sig_b) - 1.0 / 3.0).abs() < 0.08);
+
+        hasher.signature(&set(0..100), &mut sig_b);
+        assert_eq!(sig_a, sig_b);
+    }
+
+    #[test]
+    fn minhash_agreement_estimates_jaccard_similarity() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let hasher = MinHasher::new(1024, &mut rng);
+        // Shingle ids are hashes themselves, so draw them at random.
+        let ids: Vec<u32> = (0..150).map(|_| rng.gen()).collect();
+        let mut sig_a = vec![0; 1024];
+        let mut sig_b = vec![0; 1024];
+        hasher.signature(&set(ids[..100].iter().copied()), &mut sig_a);
+        hasher.signature(&set(ids[50..].iter().copied()), &mut sig_b);
+        assert!((agreement(&sig_a, &sig_b) - 1.0 / 3.0).abs() < 0.08);
+    }
+
+    #[test]
+    fn runner_up_is_the_second_smallest_hash() {
+        let hasher = MinHasher::from_seeds(vec![1, 2, 3]);
+        let mut min = vec![0; 3];
+        let mut runner_up = vec![0; 3];
+
+        hasher.runner_up_signature(&set([5]), &mut runner_up);
+        assert!(runner_up.iter().all(|value| *value == u32::MAX));
+
+        // Dropping the shingle behind each minimum leaves the runner-up as
+        // the new minimum.
+        let shingles = set(0..20);
+        hasher.signature(&shingles, &mut min);
+        hasher.runner_up_signature(&shingles, &mut runner_up);
+        for (j, seed) in [1u64, 2, 3].iter().enumerate() {
+            let single = MinHasher::from_seeds(vec![*seed]);
+            let mut value = [0];
+            let argmin = (0..20u32)
+                .find(|shingle| {
+                    single.signature(&set([*shingle]), &mut value);
+                    value[0] == min[j]
+                })
+                .unwrap();
+            single.signature(&set((0..20).filter(|s| *s != argmin)), &mut value);
+            assert_eq!(value[0], runner_up[j]);
+        }
+    }
+
+    #[test]
+    fn icws_ignores_shingles_without_a_positive_weight() {
+        let hasher = MinHasher::new(64, &mut StdRng::seed_from_u64(5));
+        let weights: IntMap<u32, f64> = [(1, 2.0), (2, 0.5), (3, 0.0), (4, -1.0)]
+            .into_iter()
+            .collect();
+        let mut with_extra = vec![0; 64];
+        let mut without = vec![0; 64];
+        hasher.weighted_signature(&set([1, 2, 3, 4, 5]), &weights, &mut with_extra);
+        hasher.weighted_signature(&set([1, 2]), &weights, &mut without);
+        assert_eq!(with_extra, without);
+
+        // Records with nothing left to sample agree with each other.
+        hasher.weighted_signature(&set([3, 4]), &weights, &mut with_extra);
+        hasher.weighted_signature(&set([]), &weights, &mut without);
+        assert_eq!(with_extra, without);
+    }
+
+    #[test]
+    fn icws_agreement_estimates_weighted_jaccard_similarity() {
+        let hasher = MinHasher::new(2048, &mut StdRng::seed_from_u64(9));
+        let shingles = set(0..50);
+        let weights: IntMap<u32, f64> = (0..50).map(|s| (s, 1.0 + s as f64 / 10.0)).collect();
+        let doubled: IntMap<u32, f64> = weights.iter().map(|(s, w)| (*s, 2.0 * w)).collect();
+
+        let mut sig_a = vec![0; 2048];
+        let mut sig_b = vec![0; 2048];
+        hasher.weighted_signature(&shingles, &weights, &mut sig_a);
+        hasher.weighted_signature(&shingles, &doubled, &mut sig_b);
+        // sum(min) / sum(max) of w and 2w is 1/2.
+        assert!((agreement(&sig_a, &sig_b) - 0.5).abs() < 0.06);
+
+        hasher.weighted_signature(&shingles, &weights.clone(), &mut sig_b);
+        assert_eq!(sig_a, sig_b);
     }
 }
@@ -0,0 +1,211 @@
+use std::borrow::Cow;
+
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Unicode normalization form applied before any other cleaning step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnicodeForm {
+    Nfc,
+    Nfkc,
+}
+
+/// A pipeline of cleaning steps applied to strings before they are shingled.
+///
+/// Steps always run in the same order, regardless of the order they were
+/// requested in: Unicode normalization, lower-casing, diacritic stripping,
+/// transliteration to ASCII, and finally collapsing of punctuation and
+/// whitespace.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Normalizer {
+    form: Option<UnicodeForm>,
+    case_fold: bool,
+    strip_accents: bool,
+    ascii: bool,
+    collapse: bool,
+}
+
+impl Normalizer {
+    /// Build a pipeline from step names as passed in from R: `"nfc"`,
+    /// `"nfkc"`, `"casefold"`, `"strip_accents"`, `"ascii"`, and `"collapse"`.
+    pub fn from_steps(steps: &[&str]) -> Result<Self, String> {
+        let mut out = Self::default();
+        for step in steps {
+            match *step {
+                "nfc" | "nfkc" if out.form.is_some() => {
+                    return Err("only one of 'nfc' and 'nfkc' may be used".to_string())
+                }
+                "nfc" => out.form = Some(UnicodeForm::Nfc),
+                "nfkc" => out.form = Some(UnicodeForm::Nfkc),
+                "casefold" => out.case_fold = true,
+                "strip_accents" => out.strip_accents = true,
+                "ascii" => out.ascii = true,
+                "collapse" => out.collapse = true,
+                other => {
+                    return Err(format!(
+                        "unknown normalization step '{other}', expected one of 'nfc', 'nfkc', \
+                         'casefold', 'strip_accents', 'ascii', or 'collapse'"
+                    ))
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// The step names that `from_steps` builds this pipeline from.
+    pub fn steps(&self) -> Vec<&'static str> {
+        let mut steps = Vec::new();
+        match self.form {
+            Some(UnicodeForm::Nfc) => steps.push("nfc"),
+            Some(UnicodeForm::Nfkc) => steps.push("nfkc"),
+            None => {}
+        }
+        for (enabled, step) in [
+            (self.case_fold, "casefold"),
+            (self.strip_accents, "strip_accents"),
+            (self.ascii, "ascii"),
+            (self.collapse, "collapse"),
+        ] {
+            if enabled {
+                steps.push(step);
+            }
+        }
+        steps
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn normalize<'a>(&self, string: &'a str) -> Cow<'a, str> {
+        if self.is_identity() {
+            return Cow::Borrowed(string);
+        }
+
+        let mut out: String = match self.form {
+            Some(UnicodeForm::Nfc) => string.nfc().collect(),
+            Some(UnicodeForm::Nfkc) => string.nfkc().collect(),
+            None => string.to_string(),
+        };
+
+        if self.case_fold {
+            let mut lowered = String::with_capacity(out.len());
+            for c in out.chars() {
+                lowercase(c, &mut lowered);
+            }
+            out = lowered;
+        }
+
+        if self.strip_accents || self.ascii {
+            out = out.nfd().filter(|c| !is_combining_mark(*c)).nfc().collect();
+        }
+
+        if self.ascii {
+            let mut ascii = String::with_capacity(out.len());
+            for c in out.nfkd().filter(|c| !is_combining_mark(*c)) {
+                transliterate(c, &mut ascii);
+            }
+            out = ascii;
+        }
+
+        if self.collapse {
+            out = out
+                .split(|c: char| c.is_whitespace() || is_punctuation(c))
+                .filter(|word| !word.is_empty())
+                .collect::<Vec<&str>>()
+                .join(" ");
+        }
+
+        Cow::Owned(out)
+    }
+}
+
+/// Push the lower-case form of `c` onto `out`. This is lower-casing rather
+/// than full Unicode case folding; the only other mappings are sharp s to
+/// "ss" and final sigma to "σ".
+fn lowercase(c: char, out: &mut String) {
+    match c {
+        'ß' | 'ẞ' => out.push_str("ss"),
+        'ς' => out.push('σ'),
+        _ => out.extend(c.to_lowercase()),
+    }
+}
+
+fn is_punctuation(c: char) -> bool {
+    c.is_ascii_punctuation()
+        || matches!(c,
+            '\u{00A1}' | '\u{00A7}' | '\u{00AB}' | '\u{00B6}' | '\u{00B7}' | '\u{00BB}' | '\u{00BF}'
+            | '\u{2010}'..='\u{2027}' | '\u{2030}'..='\u{205E}' | '\u{3001}'..='\u{3003}')
+}
+
+/// Push the ASCII form of `c` onto `out`. Letters that do not decompose into
+/// a base letter plus combining marks are spelled out; anything else outside
+/// ASCII is dropped.
+fn transliterate(c: char, out: &mut String) {
+    if c.is_ascii() {
+        out.push(c);
+        return;
+    }
+    let replacement = match c {
+        'ß' => "ss",
+        'ẞ' => "SS",
+        'æ' => "ae",
+        'Æ' => "AE",
+        'œ' => "oe",
+        'Œ' => "OE",
+        'ø' => "o",
+        'Ø' => "O",
+        'ł' => "l",
+        'Ł' => "L",
+        'đ' | 'ð' => "d",
+        'Đ' | 'Ð' => "D",
+        'þ' => "th",
+        'Þ' => "TH",
+        'ı' => "i",
+        'ħ' => "h",
+        'Ħ' => "H",
+        'ŋ' => "ng",
+        'Ŋ' => "NG",
+        '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{2032}' => "'",
+        '\u{201C}' | '\u{201D}' | '\u{201E}' | '\u{2033}' => "\"",
+        '\u{2010}'..='\u{2015}' | '\u{2212}' => "-",
+        '\u{00A0}' | '\u{2000}'..='\u{200A}' | '\u{202F}' | '\u{3000}' => " ",
+        _ => "",
+    };
+    out.push_str(replacement);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(steps: &[&str], string: &str) -> String {
+        Normalizer::from_steps(steps)
+            .unwrap()
+            .normalize(string)
+            .into_owned()
+    }
+
+    #[test]
+    fn casefold_lower_cases() {
+        assert_eq!(normalize(&["casefold"], "MÜLLER Straße"), "müller strasse");
+        assert_eq!(normalize(&["casefold"], "ΟΔΟΣ οδος"), "οδοσ οδοσ");
+        assert_eq!(normalize(&["casefold"], "İ"), "i\u{307}");
+    }
+
+    #[test]
+    fn ascii_spells_out_or_drops_other_characters() {
+        assert_eq!(normalize(&["ascii"], "Øresund Ærø"), "Oresund AEro");
+        assert_eq!(
+            normalize(&["ascii"], "“quoted”\u{00A0}—日本"),
+            "\"quoted\" -"
+        );
+        assert_eq!(
+            normalize(
+                &["nfkc", "casefold", "strip_accents", "collapse"],
+                "  Ｍüller,  GmbH "
+            ),
+            "muller gmbh"
+        );
+    }
+}
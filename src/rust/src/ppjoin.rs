@@ -0,0 +1,257 @@
+use extendr_api::prelude::*;
+use nohash_hasher::IntMap;
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use std::result::Result;
+
+use crate::interrupt::{self, interrupted, Interrupted};
+use crate::shingleset::{ShingleId, ShingleSet};
+
+/// Slack for the bounds below, which are all rounded towards keeping more
+/// candidates, so that floating point error can never cost a match.
+const EPSILON: f64 = 1e-9;
+
+/// Marks a candidate that the positional filter has ruled out.
+const PRUNED: i64 = -1;
+
+/// Exact Jaccard join of `left` and `right` by prefix filtering (AllPairs,
+/// Bayardo et al., 2007) with the length and positional filters of PPJoin
+/// (Xiao et al., 2008). Returns every `(left, right, similarity)` triple
+/// whose Jaccard similarity reaches `threshold`, which must be positive, or
+/// `Interrupted` if the user interrupts.
+///
+/// Shingles are ordered from the rarest to the most common by `doc_freqs`,
+/// and each set by that order. Two sets with similarity `t` or more must
+/// share a shingle among the first `|x| - ceil(t |x|) + 1` of each, so only
+/// those prefixes of `left` are indexed and only those of `right` probe the
+/// index. Rare shingles come first, so prefixes hit short posting lists.
+/// Candidates are then pruned on their sizes, and on whether the overlap
+/// seen so far plus what the unseen parts of both sets could add still
+/// reaches the overlap the threshold requires, before their similarity is
+/// computed exactly.
+pub fn ppjoin<T: ShingleId>(
+    left: &[ShingleSet<T>],
+    right: &[ShingleSet<T>],
+    doc_freqs: &IntMap<T, u32>,
+    threshold: f64,
+    progress: bool,
+    pool: &ThreadPool,
+) -> Result<Vec<(usize, usize, f64)>, Interrupted> {
+    let mut order: Vec<(&T, &u32)> = doc_freqs.iter().collect();
+    order.sort_unstable_by_key(|(shingle, freq)| (**freq, shingle.as_u64()));
+    let ranks: IntMap<T, u32> = order
+        .into_iter()
+        .enumerate()
+        .map(|(rank, (shingle, _))| (*shingle, rank as u32))
+        .collect();
+
+    let (left, right) = pool.install(|| {
+        let rank_sets = |sets: &[ShingleSet<T>]| -> Vec<Vec<u32>> {
+            sets.par_iter()
+                .map(|set| {
+                    let mut ranked: Vec<u32> = set.shingles.iter().map(|s| ranks[s]).collect();
+                    ranked.sort_unstable();
+                    ranked
+                })
+                .collect()
+        };
+        (rank_sets(left), rank_sets(right))
+    });
+
+    if progress {
+        rprintln!("Indexing the prefixes of {} records", left.len());
+    }
+    let mut index: IntMap<u32, Vec<(usize, usize)>> = IntMap::default();
+    for (record, set) in left.iter().enumerate() {
+        for (position, rank) in set
+            .iter()
+            .take(prefix_len(set.len(), threshold))
+            .enumerate()
+        {
+            index.entry(*rank).or_default().push((record, position));
+        }
+    }
+
+    if progress {
+        rprintln!("Probing with {} records", right.len());
+    }
+    interrupt::install(pool, || {
+        right
+            .par_iter()
+            .enumerate()
+            .filter(|_| !interrupted())
+            .flat_map_iter(|(probe, y)| {
+                let mut overlaps: IntMap<usize, i64> = IntMap::default();
+                let min_len = (threshold * y.len() as f64 - EPSILON).ceil() as usize;
+                let max_len = (y.len() as f64 / threshold + EPSILON).floor() as usize;
+
+                for (i, rank) in y.iter().take(prefix_len(y.len(), threshold)).enumerate() {
+                    let postings = match index.get(rank) {
+                        Some(postings) => postings,
+                        None => continue,
+                    };
+                    for &(record, j) in postings {
+                        let x_len = left[record].len();
+                        if x_len < min_len || x_len > max_len {
+                            continue;
+                        }
+                        let overlap = overlaps.entry(record).or_insert(0);
+                        if *overlap == PRUNED {
+                            continue;
+                        }
+                        let unseen = 1 + (x_len - j - 1).min(y.len() - i - 1);
+                        if *overlap + unseen as i64 >= required_overlap(x_len, y.len(), threshold) {
+                            *overlap += 1;
+                        } else {
+                            *overlap = PRUNED;
+                        }
+                    }
+                }
+
+                let left = &left;
+                overlaps
+                    .into_iter()
+                    .filter(|(_, overlap)| *overlap > 0)
+                    .filter_map(move |(record, _)| {
+                        let x = &left[record];
+                        let overlap = sorted_overlap(x, y);
+                        let similarity = overlap as f64 / (x.len() + y.len() - overlap) as f64;
+                        (similarity >= threshold).then_some((record, probe, similarity))
+                    })
+            })
+            .collect()
+    })
+}
+
+/// The number of leading shingles of a set of `len` that must include one
+/// shared with any set it has similarity `threshold` or more with.
+#[inline]
+fn prefix_len(len: usize, threshold: f64) -> usize {
+    let kept = (threshold * len as f64 - EPSILON).ceil() as usize;
+    (len + 1).saturating_sub(kept).min(len)
+}
+
+/// The least overlap two sets of sizes `a` and `b` need for a Jaccard
+/// similarity of `threshold`.
+#[inline]
+fn required_overlap(a: usize, b: usize, threshold: f64) -> i64 {
+    (threshold / (1.0 + threshold) * (a + b) as f64 - EPSILON).ceil() as i64
+}
+
+/// The size of the intersection of two sorted sets.
+fn sorted_overlap(a: &[u32], b: &[u32]) -> usize {
+    let (mut i, mut j, mut overlap) = (0, 0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                overlap += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    overlap
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nohash_hasher::IntSet;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use rayon::ThreadPoolBuilder;
+
+    fn set(index: usize, shingles: &[u32]) -> ShingleSet<u32> {
+        ShingleSet {
+            shingles: shingles.iter().copied().collect::<IntSet<u32>>(),
+            index,
+        }
+    }
+
+    fn doc_freqs(sets: &[&[ShingleSet<u32>]]) -> IntMap<u32, u32> {
+        let mut freqs = IntMap::default();
+        for set in sets.iter().flat_map(|sets| sets.iter()) {
+            for shingle in &set.shingles {
+                *freqs.entry(*shingle).or_insert(0) += 1;
+            }
+        }
+        freqs
+    }
+
+    #[test]
+    fn prefixes_are_long_enough_to_share_a_shingle() {
+        // A set of 10 shares at least 8 shingles with any set it has
+        // similarity 0.8 with, so one of its first 3 must be among them.
+        assert_eq!(prefix_len(10, 0.8), 3);
+        assert_eq!(prefix_len(10, 1.0), 1);
+        assert_eq!(prefix_len(10, 0.01), 10);
+        assert_eq!(prefix_len(0, 0.5), 0);
+        // ceil(0.7 * 10) is 7 despite 0.7 * 10 rounding to 7.000000000000001.
+        assert_eq!(prefix_len(10, 0.7), 4);
+    }
+
+    #[test]
+    fn required_overlap_is_the_least_reaching_the_threshold() {
+        for a in 1..20 {
+            for b in 1..20 {
+                for threshold in [0.1, 0.3, 0.5, 0.7, 0.9, 1.0] {
+                    let required = required_overlap(a, b, threshold) as usize;
+                    let jaccard = |o: usize| o as f64 / (a + b - o) as f64;
+                    if required <= a.min(b) {
+                        assert!(jaccard(required) >= threshold - EPSILON);
+                    }
+                    if required > 0 && required - 1 <= a.min(b) {
+                        assert!(jaccard(required - 1) < threshold);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sorted_overlap_counts_shared_elements() {
+        assert_eq!(sorted_overlap(&[1, 3, 5, 7], &[2, 3, 4, 7, 9]), 2);
+        assert_eq!(sorted_overlap(&[], &[1, 2]), 0);
+        assert_eq!(sorted_overlap(&[1, 2], &[1, 2]), 2);
+    }
+
+    #[test]
+    fn ppjoin_finds_exactly_the_pairs_a_brute_force_join_does() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut random_sets = |n: usize| -> Vec<ShingleSet<u32>> {
+            (0..n)
+                .map(|i| {
+                    let len = rng.gen_range(1..12);
+                    let shingles: Vec<u32> = (0..len).map(|_| rng.gen_range(0..16)).collect();
+                    set(i, &shingles)
+                })
+                .collect()
+        };
+        let left = random_sets(60);
+        let right = random_sets(60);
+        let freqs = doc_freqs(&[&left, &right]);
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+
+        for threshold in [0.2, 0.5, 0.8, 1.0] {
+            let mut found = ppjoin(&left, &right, &freqs, threshold, false, &pool).unwrap();
+            found.sort_by_key(|(x, y, _)| (*x, *y));
+
+            let mut expected = Vec::new();
+            for (x, a) in left.iter().enumerate() {
+                for (y, b) in right.iter().enumerate() {
+                    let similarity = a.jaccard_similarity(b);
+                    if similarity >= threshold {
+                        expected.push((x, y, similarity));
+                    }
+                }
+            }
+            assert_eq!(found.len(), expected.len(), "threshold {threshold}");
+            for ((x, y, s), (ex, ey, es)) in found.iter().zip(&expected) {
+                assert_eq!((x, y), (ex, ey));
+                assert!((s - es).abs() < 1e-12);
+            }
+        }
+    }
+}
@@ -1,38 +1,191 @@
-use nohash_hasher::IntSet;
+use nohash_hasher::{IntMap, IntSet, IsEnabled};
+use std::borrow::Cow;
+use std::fmt::Debug;
 use std::hash::{Hash, Hasher};
+use std::str::FromStr;
 
 use rustc_hash::FxHasher;
 
+use crate::normalize::Normalizer;
+use crate::phonetic::PhoneticEncoder;
+
+/// How a string is broken into the tokens that shingles are built from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tokenizer {
+    /// Shingles are windows of consecutive characters.
+    Char,
+    /// Shingles are windows of consecutive words, where words are separated
+    /// by whitespace or punctuation.
+    Word,
+}
+
+impl FromStr for Tokenizer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "char" => Ok(Tokenizer::Char),
+            "word" => Ok(Tokenizer::Word),
+            other => Err(format!(
+                "unknown tokenizer '{other}', expected one of 'char' or 'word'"
+            )),
+        }
+    }
+}
+
+impl Tokenizer {
+    /// The name the tokenizer is parsed from.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tokenizer::Char => "char",
+            Tokenizer::Word => "word",
+        }
+    }
+}
+
+/// Everything that controls how a string is turned into shingles.
 #[derive(Debug, Clone)]
-pub struct ShingleSet {
-    pub shingles: IntSet<u32>,
-    pub shingle_len : usize,
-    pub index : usize,
+pub struct ShingleConfig {
+    pub shingle_lens: Vec<usize>,
+    pub tokenizer: Tokenizer,
+    pub normalizer: Normalizer,
+    pub phonetic: Option<PhoneticEncoder>,
+    pub pad: bool,
+    pub multiset: bool,
 }
 
-impl ShingleSet {
-    pub fn new(string: &str, shingle_len: usize, index: usize, salt : Option<&str>) -> Self {
-        let mut out_set: IntSet<u32> = IntSet::default();
+impl ShingleConfig {
+    /// Build a config that shingles with every width in `shingle_lens`. The
+    /// shingles of each width are pooled into one set; windows of different
+    /// widths never produce the same shingle.
+    pub fn new(mut shingle_lens: Vec<usize>, tokenizer: Tokenizer) -> Self {
+        shingle_lens.sort_unstable();
+        shingle_lens.dedup();
+        Self {
+            shingle_lens,
+            tokenizer,
+            normalizer: Normalizer::default(),
+            phonetic: None,
+            pad: false,
+            multiset: false,
+        }
+    }
 
-        let char_vec: Vec<char> = string.chars().collect();
+    pub fn with_normalizer(mut self, normalizer: Normalizer) -> Self {
+        self.normalizer = normalizer;
+        self
+    }
 
-        for window in char_vec.windows(shingle_len) {
-            let mut hasher = FxHasher::default();
+    /// Replace every word by its phonetic code (after normalization and
+    /// before tokenization), so that words that sound alike share shingles.
+    pub fn with_phonetic(mut self, phonetic: Option<PhoneticEncoder>) -> Self {
+        self.phonetic = phonetic;
+        self
+    }
 
-            if let Some(salt_str) = salt {
-                salt_str.hash(&mut hasher);
-            };
+    /// Pad each token sequence with `width - 1` start sentinels and
+    /// `width - 1` end sentinels before windowing, as in classic q-gram
+    /// padding. Strings shorter than the width then still produce
+    /// shingles, and the first and last tokens appear in more shingles than
+    /// those in the middle.
+    pub fn with_padding(mut self, pad: bool) -> Self {
+        self.pad = pad;
+        self
+    }
 
-            window.hash(&mut hasher);
+    /// Keep repeated shingles apart: the k-th occurrence of a shingle in a
+    /// string becomes its own element `(shingle, k)`. The Jaccard similarity
+    /// of these occurrence-indexed sets is the generalized (multiset) Jaccard
+    /// similarity of the shingle counts, the sum of the minimum counts over
+    /// the sum of the maximum counts, so "aaaa" and "aa" are no longer
+    /// identical. MinHash over the indexed sets then estimates that
+    /// similarity without any change to the hashing.
+    pub fn with_multiset(mut self, multiset: bool) -> Self {
+        self.multiset = multiset;
+        self
+    }
+}
 
-            let result: u32 = hasher.finish() as u32;
+/// A token in a padded token sequence.
+#[derive(Hash)]
+enum Padded<T> {
+    Start,
+    Token(T),
+    End,
+}
+
+/// Integer type used to identify shingles. Shingles are hashed to 64 bits
+/// and truncated to the width of the identifier: `u32` identifiers halve the
+/// memory used by each set, while `u64` identifiers make collisions between
+/// distinct n-grams negligible even on vocabularies of many millions.
+pub trait ShingleId: Copy + Eq + Hash + IsEnabled + Debug + Send + Sync {
+    /// The width of the identifier in bits.
+    const BITS: u32;
+
+    fn from_hash(hash: u64) -> Self;
+
+    fn as_u64(self) -> u64;
+}
 
-            out_set.insert(result);
+impl ShingleId for u32 {
+    const BITS: u32 = 32;
+
+    #[inline]
+    fn from_hash(hash: u64) -> Self {
+        hash as u32
+    }
+
+    #[inline]
+    fn as_u64(self) -> u64 {
+        self as u64
+    }
+}
+
+impl ShingleId for u64 {
+    const BITS: u32 = 64;
+
+    #[inline]
+    fn from_hash(hash: u64) -> Self {
+        hash
+    }
+
+    #[inline]
+    fn as_u64(self) -> u64 {
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ShingleSet<T: ShingleId = u32> {
+    pub shingles: IntSet<T>,
+    pub index : usize,
+}
+
+impl<T: ShingleId> ShingleSet<T> {
+    pub fn new(string: &str, config: &ShingleConfig, index: usize, salt: Option<&str>) -> Self {
+        let mut out_set: IntSet<T> = IntSet::default();
+
+        let mut string = config.normalizer.normalize(string);
+        if let Some(encoder) = config.phonetic {
+            string = Cow::Owned(encoder.encode(&string));
+        }
+
+        match config.tokenizer {
+            Tokenizer::Char => {
+                let char_vec: Vec<char> = string.chars().collect();
+                insert_windows(&mut out_set, &char_vec, config, salt);
+            }
+            Tokenizer::Word => {
+                let word_vec: Vec<&str> = string
+                    .split(|c: char| !c.is_alphanumeric())
+                    .filter(|word| !word.is_empty())
+                    .collect();
+                insert_windows(&mut out_set, &word_vec, config, salt);
+            }
         }
 
         Self{
             shingles: out_set,
-            shingle_len,
             index
         }
 
@@ -47,4 +200,99 @@ impl ShingleSet {
                 / self.shingles.union(&b.shingles).count() as f64
         }
     }
+
+    /// Weighted Jaccard similarity, the sum of the weights of shared shingles
+    /// over the sum of the weights of all shingles in either set. Shingles
+    /// absent from `weights` count as weight zero.
+    pub fn weighted_jaccard_similarity(&self, b: &Self, weights: &IntMap<T, f64>) -> f64 {
+        let weight = |shingle: &T| weights.get(shingle).copied().unwrap_or(0.0);
+
+        let intersection: f64 = self.shingles.intersection(&b.shingles).map(weight).sum();
+        let union: f64 = self.shingles.union(&b.shingles).map(weight).sum();
+
+        if union > 0.0 {
+            intersection / union
+        } else {
+            0.0
+        }
+    }
+
+    /// Containment of this set in `b`: the fraction of this set's shingles
+    /// that `b` also has. Unlike the Jaccard similarity it is asymmetric, and
+    /// a short string inside a long one scores 1.
+    #[inline]
+    pub fn containment(&self, b: &Self) -> f64 {
+        if self.shingles.is_empty() {
+            0.0
+        } else {
+            self.shingles.intersection(&b.shingles).count() as f64 / self.shingles.len() as f64
+        }
+    }
+}
+
+fn insert_windows<T: Hash, S: ShingleId>(
+    out_set: &mut IntSet<S>,
+    tokens: &[T],
+    config: &ShingleConfig,
+    salt: Option<&str>,
+) {
+    // Padding an empty sequence would give every empty string the same
+    // shingles, so empty strings are left with an empty set.
+    let max_pad = match config.shingle_lens.last() {
+        Some(max_len) if config.pad && !tokens.is_empty() => max_len - 1,
+        _ => 0,
+    };
+
+    let padded: Vec<Padded<&T>> = std::iter::repeat_with(|| Padded::Start)
+        .take(max_pad)
+        .chain(tokens.iter().map(Padded::Token))
+        .chain(std::iter::repeat_with(|| Padded::End).take(max_pad))
+        .collect();
+
+    // Occurrences of each shingle seen so far, only used in multiset mode.
+    let mut counts: IntMap<u64, u64> = IntMap::default();
+
+    for &shingle_len in &config.shingle_lens {
+        // Each width only needs `shingle_len - 1` sentinels on either side.
+        let trim = max_pad - max_pad.min(shingle_len - 1);
+        let tokens = &padded[trim..padded.len() - trim];
+
+        // Slices hash their length before their contents, so windows of
+        // different widths are tagged by width and cannot collide except
+        // through the truncation to the shingle id width.
+        for window in tokens.windows(shingle_len) {
+            let mut hash = hash_window(window, salt);
+            if config.multiset {
+                let seen = counts.entry(hash).or_insert(0);
+                hash = occurrence_hash(hash, *seen);
+                *seen += 1;
+            }
+            out_set.insert(S::from_hash(hash));
+        }
+    }
+}
+
+fn hash_window<T: Hash>(window: &[T], salt: Option<&str>) -> u64 {
+    let mut hasher = FxHasher::default();
+
+    if let Some(salt_str) = salt {
+        salt_str.hash(&mut hasher);
+    };
+
+    window.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+/// Identifier of the `occurrence`-th (zero-based) copy of a shingle. The
+/// first copy keeps the shingle's own hash, so strings without repeated
+/// shingles get the same set as in set mode.
+fn occurrence_hash(hash: u64, occurrence: u64) -> u64 {
+    if occurrence == 0 {
+        return hash;
+    }
+    let mut hasher = FxHasher::default();
+    hash.hash(&mut hasher);
+    occurrence.hash(&mut hasher);
+    hasher.finish()
 }
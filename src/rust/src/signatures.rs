@@ -0,0 +1,799 @@
+use dashmap::DashMap;
+use extendr_api::prelude::*;
+use ndarray::{Array2, ArrayView2};
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use rustc_hash::FxHasher;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::hash::{Hash, Hasher};
+use std::result::Result;
+use std::time::Instant;
+
+use crate::buckets::BucketLimit;
+use crate::diagnostics::{JoinDiagnostics, ProbeCounts};
+use crate::interrupt::{self, interrupted, Interrupted};
+
+/// MinHash signatures for a collection of records, stored row-major in one
+/// flat buffer. Each signature value keeps only its lowest `bits` bits
+/// (b-bit MinHash, Li & König 2010), and values are bit-packed, so a record
+/// takes `ceil(len * bits / 8)` bytes. Band `b` of a signature is the
+/// `band_size` values starting at `b * band_size`.
+///
+/// Keeping fewer bits shrinks signatures at the cost of chance collisions:
+/// two values agree with probability `2^-bits + (1 - 2^-bits) J` rather
+/// than `J`, which `estimate` corrects for.
+#[derive(Debug, Clone)]
+pub struct Signatures {
+    bytes: Vec<u8>,
+    len: usize,
+    bits: u32,
+    stride: usize,
+}
+
+impl Signatures {
+    /// Compute the signatures of `n_records` records in parallel, where
+    /// `sign(i, out)` writes the full 32-bit signature of record `i` into
+    /// `out`, keeping the lowest `bits` bits of each value. Stops early if
+    /// the user interrupts.
+    pub fn compute<F>(
+        n_records: usize,
+        len: usize,
+        bits: u32,
+        pool: &ThreadPool,
+        sign: F,
+    ) -> Result<Self, Interrupted>
+    where
+        F: Fn(usize, &mut [u32]) + Sync,
+    {
+        let mut out = Self::zeroed(n_records, len, bits);
+        if out.stride > 0 {
+            let stride = out.stride;
+            interrupt::install(pool, || {
+                out.bytes
+                    .par_chunks_mut(stride)
+                    .enumerate()
+                    .for_each(|(i, record)| {
+                        if interrupted() {
+                            return;
+                        }
+                        let mut values = vec![0u32; len];
+                        sign(i, &mut values);
+                        for (j, value) in values.into_iter().enumerate() {
+                            put(record, j * bits as usize, bits, value);
+                        }
+                    });
+            })?;
+        }
+        Ok(out)
+    }
+
+    fn zeroed(n_records: usize, len: usize, bits: u32) -> Self {
+        assert!(
+            (1..=32).contains(&bits),
+            "signatures keep between 1 and 32 bits"
+        );
+        let stride = (len * bits as usize + 7) / 8;
+        Self {
+            bytes: vec![0u8; n_records * stride],
+            len,
+            bits,
+            stride,
+        }
+    }
+
+    /// Full 32-bit signatures from a matrix with one record per row, such as
+    /// a signature matrix passed back in from R. Every value must be a whole
+    /// number that fits in 32 bits, as `to_matrix` writes them.
+    pub fn from_matrix(matrix: ArrayView2<f64>) -> Result<Self, String> {
+        let mut out = Self::zeroed(matrix.nrows(), matrix.ncols(), 32);
+        for (i, row) in matrix.rows().into_iter().enumerate() {
+            let record = &mut out.bytes[i * out.stride..(i + 1) * out.stride];
+            for (j, value) in row.iter().enumerate() {
+                if value.fract() != 0.0 || !(0.0..=u32::MAX as f64).contains(value) {
+                    return Err(
+                        "signature matrices must hold whole numbers between 0 and 2^32 - 1"
+                            .to_string(),
+                    );
+                }
+                put(record, j * 32, 32, *value as u32);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Signatures from their packed bytes, as returned by `packed`.
+    pub fn from_packed(bytes: &[u8], len: usize, bits: u32) -> Result<Self, String> {
+        let mut out = Self::zeroed(0, len, bits);
+        if out.stride > 0 && bytes.len() % out.stride != 0 {
+            return Err("packed signatures do not match n_bands * band_width and bits".to_string());
+        }
+        out.bytes = bytes.to_vec();
+        Ok(out)
+    }
+
+    /// The signatures as a matrix with one record per row. Values are at
+    /// most 32 bits, so they are represented exactly as doubles.
+    pub fn to_matrix(&self) -> Array2<f64> {
+        Array2::from_shape_fn((self.n_records(), self.len), |(i, j)| {
+            self.value(i, j) as f64
+        })
+    }
+
+    /// The packed bytes of every signature, record after record.
+    pub fn packed(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn n_records(&self) -> usize {
+        self.bytes.len().checked_div(self.stride).unwrap_or(0)
+    }
+
+    /// The length of each signature.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    #[inline]
+    fn record(&self, record: usize) -> &[u8] {
+        &self.bytes[record * self.stride..(record + 1) * self.stride]
+    }
+
+    #[inline]
+    pub fn value(&self, record: usize, j: usize) -> u32 {
+        get(self.record(record), j * self.bits as usize, self.bits)
+    }
+
+    /// Hash band `band` of a record's signature into a single bucket key.
+    #[inline]
+    pub fn band_key(&self, record: usize, band: usize, band_size: usize) -> u64 {
+        let mut hasher = FxHasher::default();
+        for j in band * band_size..(band + 1) * band_size {
+            self.value(record, j).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// The bucket keys a record looks in for `band`: its own, followed by
+    /// those of the variants `probe` adds, if any. A variant replaces one of
+    /// the first `n_probes` values of the band by the record's runner-up
+    /// value there, and is skipped when the two values are equal.
+    pub fn probe_keys(
+        &self,
+        record: usize,
+        band: usize,
+        band_size: usize,
+        probe: Option<&MultiProbe>,
+    ) -> Vec<u64> {
+        let mut keys = vec![self.band_key(record, band, band_size)];
+        let probe = match probe {
+            Some(probe) => probe,
+            None => return keys,
+        };
+
+        let start = band * band_size;
+        for replaced in start..start + probe.n_probes.min(band_size) {
+            let runner_up = probe.runner_up.value(record, replaced);
+            if runner_up == self.value(record, replaced) {
+                continue;
+            }
+            let mut hasher = FxHasher::default();
+            for j in start..start + band_size {
+                let value = if j == replaced {
+                    runner_up
+                } else {
+                    self.value(record, j)
+                };
+                value.hash(&mut hasher);
+            }
+            keys.push(hasher.finish());
+        }
+        keys
+    }
+
+    /// The fraction of positions at which two signatures agree.
+    #[inline]
+    pub fn agreement(&self, record: usize, other: &Self, other_record: usize) -> f64 {
+        let agree = (0..self.len)
+            .filter(|&j| self.value(record, j) == other.value(other_record, j))
+            .count();
+        agree as f64 / self.len as f64
+    }
+
+    /// An estimate of the Jaccard similarity of two records from the
+    /// agreement of their signatures, corrected for the chance collisions of
+    /// b-bit values. Full 32-bit signatures are taken at face value.
+    #[inline]
+    pub fn estimate(&self, record: usize, other: &Self, other_record: usize) -> f64 {
+        let agreement = self.agreement(record, other, other_record);
+        if self.bits == 32 {
+            return agreement;
+        }
+        let chance = 0.5f64.powi(self.bits as i32);
+        ((agreement - chance) / (1.0 - chance)).clamp(0.0, 1.0)
+    }
+
+    /// The standard error of `estimate` for signatures of this length. Each
+    /// value agrees independently with probability `P = c + (1 - c) J`,
+    /// where `c` is the chance agreement of b-bit values, so the agreement
+    /// has variance `P (1 - P) / len`, scaled by `1 / (1 - c)^2` in the
+    /// corrected estimate.
+    pub fn standard_error(&self, estimate: f64) -> f64 {
+        let chance = if self.bits == 32 {
+            0.0
+        } else {
+            0.5f64.powi(self.bits as i32)
+        };
+        let p = chance + (1.0 - chance) * estimate;
+        (p * (1.0 - p) / self.len as f64).sqrt() / (1.0 - chance)
+    }
+}
+
+/// Write the lowest `bits` bits of `value` at bit `offset` of `record`.
+#[inline]
+fn put(record: &mut [u8], offset: usize, bits: u32, value: u32) {
+    let value = value as u64 & mask(bits);
+    for bit in 0..bits as usize {
+        if value >> bit & 1 == 1 {
+            let at = offset + bit;
+            record[at / 8] |= 1 << (at % 8);
+        }
+    }
+}
+
+/// Read the `bits`-bit value at bit `offset` of `record`.
+#[inline]
+fn get(record: &[u8], offset: usize, bits: u32) -> u32 {
+    let start = offset / 8;
+    let end = (start + 8).min(record.len());
+    let mut window = [0u8; 8];
+    window[..end - start].copy_from_slice(&record[start..end]);
+    ((u64::from_le_bytes(window) >> (offset % 8)) & mask(bits)) as u32
+}
+
+#[inline]
+fn mask(bits: u32) -> u64 {
+    (1u64 << bits) - 1
+}
+
+/// Multi-probe banding for MinHash. Besides its own bucket, a probing
+/// record also looks in the buckets of band variants that swap one value
+/// for the runner-up value of the same hash function (the second-smallest
+/// hash of the record's shingles). A similar record whose minimum differs
+/// from the probing record's in that one position, because the probing
+/// record's minimum shingle is missing from it, then still shares a bucket,
+/// so fewer bands reach the same recall.
+pub struct MultiProbe<'a> {
+    /// Runner-up signatures of the probing records.
+    pub runner_up: &'a Signatures,
+    /// How many variants to probe per band, at most the band size.
+    pub n_probes: usize,
+}
+
+/// Bucket both sides band by band and return the `(larger, smaller)` record
+/// pairs that share a bucket in at least one band and whose `similarity`
+/// reaches `threshold`, along with that similarity. With `probe`, records of
+/// `larger` also look in the buckets of their multi-probe variants. Buckets
+/// that `limit` refuses are not verified, and the work done in each band is
+/// logged in `diagnostics`. If the user interrupts, the join stops at the
+/// next record and returns `Interrupted`.
+#[allow(clippy::too_many_arguments)]
+pub fn band_join<F>(
+    smaller: &Signatures,
+    larger: &Signatures,
+    probe: Option<&MultiProbe>,
+    limit: &BucketLimit,
+    diagnostics: &JoinDiagnostics,
+    n_bands: usize,
+    band_size: usize,
+    threshold: f64,
+    progress: bool,
+    pool: &ThreadPool,
+    similarity: F,
+) -> Result<DashMap<(usize, usize), f64>, Interrupted>
+where
+    F: Fn(usize, usize) -> f64 + Sync,
+{
+    let matched_pairs: DashMap<(usize, usize), f64> = DashMap::new();
+
+    let small_set_map: DashMap<u64, Vec<usize>> = DashMap::with_capacity(smaller.n_records());
+    for i in 0..n_bands {
+        if progress {
+            rprintln!("starting band {i} out of {n_bands}");
+        }
+        let started = Instant::now();
+
+        interrupt::install(pool, || {
+            (0..smaller.n_records()).into_par_iter().for_each(|index| {
+                let key = smaller.band_key(index, i, band_size);
+
+                small_set_map
+                    .entry(key)
+                    .and_modify(|x| x.push(index))
+                    .or_insert(vec![index]);
+            });
+            limit.count_buckets(&small_set_map);
+            (0..larger.n_records()).into_par_iter().for_each(|index| {
+                if interrupted() {
+                    return;
+                }
+                let mut counts = ProbeCounts::default();
+                for key in larger.probe_keys(index, i, band_size, probe) {
+                    if let Some(matches) = small_set_map.get(&key) {
+                        counts.candidates += matches.len();
+                        if !limit.admits(matches.len()) {
+                            continue;
+                        }
+                        for matched in matches.iter() {
+                            if matched_pairs.contains_key(&(index, *matched)) {
+                                continue;
+                            }
+                            counts.verified += 1;
+                            let score = similarity(index, *matched);
+                            if score >= threshold {
+                                matched_pairs.insert((index, *matched), score);
+                                counts.accepted += 1;
+                            }
+                        }
+                    }
+                }
+                diagnostics.add(counts);
+            });
+        })?;
+
+        diagnostics.finish_band(started, &small_set_map, progress);
+        small_set_map.clear()
+    }
+
+    Ok(matched_pairs)
+}
+
+/// Bucket both sides band by band, as `band_join` does, but return every
+/// `(larger, smaller)` pair that shares a bucket in at least one band
+/// without verifying it, along with the number of bands it shares a bucket
+/// in. A pair found through several multi-probe variants of one band is
+/// counted once for that band.
+#[allow(clippy::too_many_arguments)]
+pub fn band_candidates(
+    smaller: &Signatures,
+    larger: &Signatures,
+    probe: Option<&MultiProbe>,
+    limit: &BucketLimit,
+    diagnostics: &JoinDiagnostics,
+    n_bands: usize,
+    band_size: usize,
+    progress: bool,
+    pool: &ThreadPool,
+) -> Result<DashMap<(usize, usize), u32>, Interrupted> {
+    let collisions: DashMap<(usize, usize), u32> = DashMap::new();
+
+    let small_set_map: DashMap<u64, Vec<usize>> = DashMap::with_capacity(smaller.n_records());
+    for i in 0..n_bands {
+        if progress {
+            rprintln!("starting band {i} out of {n_bands}");
+        }
+        let started = Instant::now();
+
+        interrupt::install(pool, || {
+            (0..smaller.n_records()).into_par_iter().for_each(|index| {
+                let key = smaller.band_key(index, i, band_size);
+
+                small_set_map
+                    .entry(key)
+                    .and_modify(|x| x.push(index))
+                    .or_insert(vec![index]);
+            });
+            limit.count_buckets(&small_set_map);
+            (0..larger.n_records()).into_par_iter().for_each(|index| {
+                if interrupted() {
+                    return;
+                }
+                // Each smaller record sits in one bucket per band, so once
+                // repeated keys are dropped it is found at most once.
+                let mut keys = larger.probe_keys(index, i, band_size, probe);
+                keys.sort_unstable();
+                keys.dedup();
+                let mut counts = ProbeCounts::default();
+                for key in keys {
+                    let matches = match small_set_map.get(&key) {
+                        Some(matches) => matches,
+                        None => continue,
+                    };
+                    counts.candidates += matches.len();
+                    if !limit.admits(matches.len()) {
+                        continue;
+                    }
+                    for matched in matches.iter() {
+                        let mut n = collisions.entry((index, *matched)).or_insert(0);
+                        if *n == 0 {
+                            counts.accepted += 1;
+                        }
+                        *n += 1;
+                    }
+                }
+                diagnostics.add(counts);
+            });
+        })?;
+
+        diagnostics.finish_band(started, &small_set_map, progress);
+        small_set_map.clear()
+    }
+
+    Ok(collisions)
+}
+
+/// Join a set of signatures against itself, returning the `(i, j)` pairs
+/// with `i < j` that share a bucket in at least one band and whose
+/// `similarity` reaches `threshold`, along with that similarity. Records are
+/// bucketed once per band, and each record is only compared with the records
+/// before it in its bucket, so no pair is compared in both orders and no
+/// record is compared with itself. Buckets that `limit` refuses are not
+/// verified, and what each band found is logged in `diagnostics`.
+#[allow(clippy::too_many_arguments)]
+pub fn band_self_join<F>(
+    signatures: &Signatures,
+    limit: &BucketLimit,
+    diagnostics: &JoinDiagnostics,
+    n_bands: usize,
+    band_size: usize,
+    threshold: f64,
+    progress: bool,
+    pool: &ThreadPool,
+    similarity: F,
+) -> Result<DashMap<(usize, usize), f64>, Interrupted>
+where
+    F: Fn(usize, usize) -> f64 + Sync,
+{
+    let matched_pairs: DashMap<(usize, usize), f64> = DashMap::new();
+
+    let set_map: DashMap<u64, Vec<usize>> = DashMap::with_capacity(signatures.n_records());
+    for i in 0..n_bands {
+        if progress {
+            rprintln!("starting band {i} out of {n_bands}");
+        }
+        let started = Instant::now();
+
+        interrupt::install(pool, || {
+            let keys: Vec<u64> = (0..signatures.n_records())
+                .into_par_iter()
+                .map(|index| signatures.band_key(index, i, band_size))
+                .collect();
+
+            keys.par_iter().enumerate().for_each(|(index, key)| {
+                set_map
+                    .entry(*key)
+                    .and_modify(|x| x.push(index))
+                    .or_insert(vec![index]);
+            });
+            limit.count_buckets(&set_map);
+            keys.par_iter().enumerate().for_each(|(index, key)| {
+                if interrupted() {
+                    return;
+                }
+                let mut counts = ProbeCounts::default();
+                let bucket = set_map.get(key).unwrap();
+                let earlier = || bucket.iter().filter(|other| **other < index);
+                counts.candidates = earlier().count();
+                if limit.admits_pairs(bucket.len(), counts.candidates) {
+                    for other in earlier() {
+                        if matched_pairs.contains_key(&(*other, index)) {
+                            continue;
+                        }
+                        counts.verified += 1;
+                        let score = similarity(*other, index);
+                        if score >= threshold {
+                            matched_pairs.insert((*other, index), score);
+                            counts.accepted += 1;
+                        }
+                    }
+                }
+                diagnostics.add(counts);
+            });
+        })?;
+
+        diagnostics.finish_band(started, &set_map, progress);
+        set_map.clear()
+    }
+
+    Ok(matched_pairs)
+}
+
+/// A candidate neighbour of a record, found in band `band`. Neighbours are
+/// ordered from worst to best: by similarity, then by lower index, so that
+/// ties are broken the same way whatever order candidates are found in.
+#[derive(Debug, Clone, Copy)]
+struct Neighbour {
+    similarity: f64,
+    index: usize,
+    band: usize,
+}
+
+impl PartialEq for Neighbour {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Neighbour {}
+
+impl PartialOrd for Neighbour {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Neighbour {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.similarity
+            .total_cmp(&other.similarity)
+            .then_with(|| other.index.cmp(&self.index))
+    }
+}
+
+/// Like `band_join`, but keep only the `k` most similar `larger` records for
+/// each `smaller` record, among candidates whose `similarity` reaches
+/// `threshold`. Each record holds a heap of at most `k` neighbours across
+/// all bands, so memory stays bounded however many candidates collide.
+/// Returns `(smaller, larger, similarity)` triples, grouped by `smaller` and
+/// from most to least similar.
+#[allow(clippy::too_many_arguments)]
+pub fn band_top_k<F>(
+    smaller: &Signatures,
+    larger: &Signatures,
+    probe: Option<&MultiProbe>,
+    limit: &BucketLimit,
+    diagnostics: &JoinDiagnostics,
+    n_bands: usize,
+    band_size: usize,
+    k: usize,
+    threshold: f64,
+    progress: bool,
+    pool: &ThreadPool,
+    similarity: F,
+) -> Result<Vec<(usize, usize, f64)>, Interrupted>
+where
+    F: Fn(usize, usize) -> f64 + Sync,
+{
+    if k == 0 {
+        return Ok(Vec::new());
+    }
+    let heaps: DashMap<usize, BinaryHeap<Reverse<Neighbour>>> = DashMap::new();
+
+    let small_set_map: DashMap<u64, Vec<usize>> = DashMap::with_capacity(smaller.n_records());
+    for i in 0..n_bands {
+        if progress {
+            rprintln!("starting band {i} out of {n_bands}");
+        }
+        let started = Instant::now();
+
+        interrupt::install(pool, || {
+            (0..smaller.n_records()).into_par_iter().for_each(|index| {
+                let key = smaller.band_key(index, i, band_size);
+
+                small_set_map
+                    .entry(key)
+                    .and_modify(|x| x.push(index))
+                    .or_insert(vec![index]);
+            });
+            limit.count_buckets(&small_set_map);
+            (0..larger.n_records()).into_par_iter().for_each(|index| {
+                if interrupted() {
+                    return;
+                }
+                let mut counts = ProbeCounts::default();
+                for key in larger.probe_keys(index, i, band_size, probe) {
+                    let matches = match small_set_map.get(&key) {
+                        Some(matches) => matches,
+                        None => continue,
+                    };
+                    counts.candidates += matches.len();
+                    if !limit.admits(matches.len()) {
+                        continue;
+                    }
+                    for matched in matches.iter() {
+                        // A pair kept in an earlier band, or through an
+                        // earlier probe of this one, is not compared again.
+                        // One that was dropped is worse than every neighbour
+                        // kept since, so verifying it again only loses again.
+                        let kept = heaps.get(matched).is_some_and(|heap| {
+                            heap.iter().any(|neighbour| neighbour.0.index == index)
+                        });
+                        if kept {
+                            continue;
+                        }
+
+                        counts.verified += 1;
+                        let score = similarity(index, *matched);
+                        if score < threshold {
+                            continue;
+                        }
+
+                        let candidate = Neighbour {
+                            similarity: score,
+                            index,
+                            band: i,
+                        };
+                        let mut heap = heaps.entry(*matched).or_default();
+                        if heap.len() < k {
+                            heap.push(Reverse(candidate));
+                        } else if heap.peek().is_some_and(|worst| candidate > worst.0) {
+                            heap.pop();
+                            heap.push(Reverse(candidate));
+                        }
+                    }
+                }
+                diagnostics.add(counts);
+            });
+        })?;
+
+        diagnostics.finish_band(started, &small_set_map, progress);
+        small_set_map.clear()
+    }
+
+    // Neighbours can be pushed out of a heap by better ones from later
+    // bands, so a band's accepted pairs are only known once every band is
+    // done: they are the neighbours it found that were kept.
+    let mut accepted = vec![0; n_bands];
+    let mut out: Vec<(usize, usize, f64)> = Vec::new();
+    let mut heaps: Vec<(usize, BinaryHeap<Reverse<Neighbour>>)> = heaps.into_iter().collect();
+    heaps.sort_unstable_by_key(|(record, _)| *record);
+    for (record, heap) in heaps {
+        // Sorting the reversed neighbours ascending puts the best first.
+        for Reverse(neighbour) in heap.into_sorted_vec() {
+            accepted[neighbour.band] += 1;
+            out.push((record, neighbour.index, neighbour.similarity));
+        }
+    }
+    diagnostics.set_accepted(&accepted);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::arr2;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use rayon::ThreadPoolBuilder;
+
+    fn pool() -> ThreadPool {
+        ThreadPoolBuilder::new().num_threads(2).build().unwrap()
+    }
+
+    #[test]
+    fn packed_values_round_trip_at_every_width() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for bits in 1..=32 {
+            let len = 13;
+            let mut record = vec![0u8; (len * bits as usize + 7) / 8];
+            let values: Vec<u32> = (0..len).map(|_| rng.gen()).collect();
+            for (j, value) in values.iter().enumerate() {
+                put(&mut record, j * bits as usize, bits, *value);
+            }
+            for (j, value) in values.iter().enumerate() {
+                assert_eq!(
+                    get(&record, j * bits as usize, bits) as u64,
+                    *value as u64 & mask(bits),
+                    "bits {bits}, value {j}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn computed_signatures_keep_the_lowest_bits() {
+        for bits in [1, 2, 7, 8, 16, 31, 32] {
+            let signatures = Signatures::compute(5, 9, bits, &pool(), |i, out| {
+                for (j, value) in out.iter_mut().enumerate() {
+                    *value = u32::MAX - (i * 31 + j) as u32;
+                }
+            })
+            .unwrap();
+            assert_eq!(signatures.n_records(), 5);
+            assert_eq!(signatures.len(), 9);
+            assert_eq!(signatures.packed().len(), 5 * ((9 * bits as usize + 7) / 8));
+            for i in 0..5 {
+                for j in 0..9 {
+                    let full = u32::MAX - (i * 31 + j) as u32;
+                    assert_eq!(signatures.value(i, j) as u64, full as u64 & mask(bits));
+                }
+            }
+
+            let unpacked = Signatures::from_packed(signatures.packed(), 9, bits).unwrap();
+            assert_eq!(unpacked.packed(), signatures.packed());
+            assert_eq!(unpacked.to_matrix(), signatures.to_matrix());
+        }
+    }
+
+    #[test]
+    fn packed_signatures_must_fill_whole_records() {
+        // 9 values of 7 bits take 8 bytes per record.
+        assert!(Signatures::from_packed(&[0; 16], 9, 7).is_ok());
+        assert!(Signatures::from_packed(&[0; 15], 9, 7).is_err());
+        assert_eq!(Signatures::from_packed(&[], 9, 7).unwrap().n_records(), 0);
+    }
+
+    #[test]
+    fn matrices_round_trip_and_reject_values_outside_32_bits() {
+        let matrix = arr2(&[[0.0, 1.0, u32::MAX as f64], [5.0, 6.0, 7.0]]);
+        let signatures = Signatures::from_matrix(matrix.view()).unwrap();
+        assert_eq!(signatures.to_matrix(), matrix);
+
+        for bad in [-1.0, 0.5, 2f64.powi(32), f64::NAN] {
+            let matrix = arr2(&[[0.0, bad]]);
+            assert!(Signatures::from_matrix(matrix.view()).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn b_bit_estimates_remove_chance_agreement() {
+        let sign = |bits, values: [u32; 4]| {
+            Signatures::compute(1, 4, bits, &pool(), |_, out| out.copy_from_slice(&values)).unwrap()
+        };
+        let (a, b) = (sign(32, [0, 0, 0, 0]), sign(32, [0, 0, 1, 1]));
+        assert_eq!(a.estimate(0, &b, 0), 0.5);
+
+        // One bit values agree half the time by chance, so half agreement
+        // means no similarity at all.
+        let (a, b) = (sign(1, [0, 0, 0, 0]), sign(1, [0, 0, 1, 1]));
+        assert_eq!(a.estimate(0, &b, 0), 0.0);
+        assert_eq!(a.estimate(0, &a, 0), 1.0);
+    }
+
+    #[test]
+    fn band_joins_skip_buckets_over_the_limit() {
+        // Three records in one bucket of every band, and one on its own.
+        let smaller = Signatures::from_matrix(
+            arr2(&[
+                [1.0, 1.0, 2.0, 2.0],
+                [1.0, 1.0, 2.0, 2.0],
+                [1.0, 1.0, 2.0, 2.0],
+                [3.0, 3.0, 4.0, 4.0],
+            ])
+            .view(),
+        )
+        .unwrap();
+        let larger = smaller.clone();
+        let join = |limit: &BucketLimit, diagnostics: &JoinDiagnostics| {
+            band_join(
+                &smaller,
+                &larger,
+                None,
+                limit,
+                diagnostics,
+                2,
+                2,
+                0.5,
+                false,
+                &pool(),
+                |x, y| smaller.estimate(y, &larger, x),
+            )
+            .unwrap()
+        };
+
+        let unlimited = BucketLimit::new(None);
+        assert_eq!(join(&unlimited, &JoinDiagnostics::default()).len(), 10);
+        assert_eq!(unlimited.dropped_buckets(), 0);
+        assert_eq!(unlimited.dropped_pairs(), 0);
+
+        let limit = BucketLimit::new(Some(2));
+        let diagnostics = JoinDiagnostics::default();
+        let matches = join(&limit, &diagnostics);
+        assert_eq!(matches.len(), 1);
+        assert!(matches.contains_key(&(3, 3)));
+        // The large bucket is refused in both bands, to each of its three
+        // probes.
+        assert_eq!(limit.dropped_buckets(), 2);
+        assert_eq!(limit.dropped_pairs(), 2 * 3 * 3);
+        // Refused candidates still count, and the lone pair is only
+        // verified by the first band.
+        let bands = diagnostics.bands();
+        assert!(bands.iter().all(|band| band.candidates == 10));
+        assert_eq!((bands[0].verified, bands[0].accepted), (1, 1));
+        assert_eq!((bands[1].verified, bands[1].accepted), (0, 0));
+    }
+}